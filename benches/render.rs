@@ -0,0 +1,118 @@
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use lila_gif::{
+    api::{BoardTheme, RequestBody, RequestFrame},
+    render::{Format, Render},
+    theme::{Theme, Themes},
+};
+
+/// Positions before and after a move, from different phases of a game.
+const POSITIONS: [(&str, &str, &str, &str); 3] = [
+    (
+        "opening",
+        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+        "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1",
+        "e2e4",
+    ),
+    (
+        "middlegame",
+        "r1bq1rk1/pp2bppp/2n1pn2/3p4/2PP4/2N2N2/PP2BPPP/R2QKB1R w KQ - 0 9",
+        "r1bq1rk1/pp2bppp/2n1pn2/3P4/3P4/2N2N2/PP2BPPP/R2QKB1R b KQ - 0 9",
+        "c4d5",
+    ),
+    (
+        "endgame",
+        "8/5pk1/6p1/3R4/5P2/6PK/r7/8 w - - 0 40",
+        "8/5pk1/6p1/8/5P2/6PK/r7/3R4 b - - 1 40",
+        "d5d1",
+    ),
+];
+
+fn theme() -> &'static Theme {
+    let themes: &'static Themes = Box::leak(Box::new(Themes::new()));
+    themes.get(None, BoardTheme::default())
+}
+
+fn animation(theme: &'static Theme, frames: &[(&str, Option<&str>)]) -> Render {
+    let body = RequestBody::builder()
+        .frames(frames.iter().map(|&(fen, last_move)| RequestFrame {
+            fen: fen.parse().expect("valid fen"),
+            last_move: last_move.map(|uci| uci.parse().expect("valid uci")),
+            ..RequestFrame::default()
+        }))
+        .end_frame(false)
+        .build()
+        .expect("valid request");
+    Render::new_animation(theme, body, Format::Gif)
+}
+
+/// Render that has already drawn the first position, so that the next
+/// frame only renders the changes of the move.
+fn after_first_frame(theme: &'static Theme, before: &str, after: &str, uci: &str) -> Render {
+    let mut render = animation(theme, &[(before, None), (after, Some(uci))]);
+    render.render_next_diff().expect("first frame");
+    render
+}
+
+fn render_diff(c: &mut Criterion) {
+    let theme = theme();
+    let mut group = c.benchmark_group("render_diff");
+    group.bench_function("first frame", |b| {
+        b.iter_batched(
+            || animation(theme, &[(POSITIONS[1].1, None)]),
+            |mut render| render.render_next_diff().expect("render"),
+            BatchSize::SmallInput,
+        )
+    });
+    for (name, before, after, uci) in POSITIONS {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || after_first_frame(theme, before, after, uci),
+                |mut render| render.render_next_diff().expect("render"),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn encode_frame(c: &mut Criterion) {
+    let theme = theme();
+    let mut group = c.benchmark_group("encode_frame");
+    group.bench_function("first frame", |b| {
+        b.iter_batched(
+            || {
+                let mut render = animation(theme, &[(POSITIONS[1].1, None)]);
+                let frame = render.render_next_diff().expect("render").expect("frame");
+                (render, frame)
+            },
+            |(mut render, frame)| {
+                let mut output = BytesMut::new();
+                render.encode_rendered(&mut output, &frame).expect("encode");
+                output
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    for (name, before, after, uci) in POSITIONS {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    let mut render = after_first_frame(theme, before, after, uci);
+                    let frame = render.render_next_diff().expect("render").expect("frame");
+                    (render, frame)
+                },
+                |(mut render, frame)| {
+                    let mut output = BytesMut::new();
+                    render.encode_rendered(&mut output, &frame).expect("encode");
+                    output
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, render_diff, encode_frame);
+criterion_main!(benches);
@@ -0,0 +1,36 @@
+#![no_main]
+
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use lila_gif::{
+    api::RequestBody,
+    render::{Format, Render},
+    theme::Themes,
+};
+
+static THEMES: OnceLock<Themes> = OnceLock::new();
+
+// The first byte picks the format, the rest is the JSON body of
+// `POST /game.gif`.
+fuzz_target!(|data: &[u8]| {
+    let Some((&format, body)) = data.split_first() else {
+        return;
+    };
+    let format = match format % 3 {
+        0 => Format::Gif,
+        1 => Format::Apng,
+        _ => Format::Webp,
+    };
+    let Ok(body) = serde_json::from_slice::<RequestBody>(body) else {
+        return;
+    };
+    let Ok(render) = Render::try_from_request(THEMES.get_or_init(Themes::new), body, format) else {
+        return;
+    };
+    for part in render {
+        if part.is_err() {
+            break;
+        }
+    }
+});
@@ -0,0 +1,45 @@
+#![no_main]
+
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use lila_gif::{
+    api::RequestParams,
+    cache::ImageCache,
+    render::{Format, Render},
+    theme::Themes,
+};
+
+static THEMES: OnceLock<Themes> = OnceLock::new();
+static CACHE: OnceLock<ImageCache> = OnceLock::new();
+
+// The first byte picks the format and whether to go through the image
+// cache, the rest is the query string of `GET /image.gif`.
+fuzz_target!(|data: &[u8]| {
+    let Some((&byte, query)) = data.split_first() else {
+        return;
+    };
+    let format = if byte % 2 == 0 {
+        Format::Gif
+    } else {
+        Format::Png
+    };
+    let Ok(params) = serde_urlencoded::from_bytes::<RequestParams>(query) else {
+        return;
+    };
+    let themes = THEMES.get_or_init(Themes::new);
+    let render = if byte & 2 == 0 {
+        Render::try_from_params(themes, params, format)
+    } else {
+        let cache = CACHE.get_or_init(|| ImageCache::new(16));
+        Render::try_from_cached_params(cache, themes, params, format)
+    };
+    let Ok(render) = render else {
+        return;
+    };
+    for part in render {
+        if part.is_err() {
+            break;
+        }
+    }
+});
@@ -1,26 +1,144 @@
-use std::fmt;
+use std::{error, fmt, num::NonZeroU16, str::FromStr};
 
 use arrayvec::ArrayString;
 use serde::{de, Deserialize};
-use serde_with::{serde_as, DisplayFromStr};
+use serde_with::{serde_as, CommaSeparator, DisplayFromStr, StringWithSeparator};
 use shakmaty::{
-    fen::Fen, san::San, uci::Uci, CastlingMode, Chess, EnPassantMode, Position, Setup, Square,
+    fen::Fen, uci::Uci, Bitboard, CastlingMode, Chess, Color, FromSetup, PositionError,
+    PositionErrorKinds, Setup, Square,
 };
 
-#[derive(Deserialize, PartialEq, Eq, Copy, Clone)]
-pub enum Orientation {
-    #[serde(rename = "white")]
-    White,
-    #[serde(rename = "black")]
-    Black,
+use crate::pgn;
+
+mod builder;
+
+pub use builder::{Missing, Present, RequestBodyBuilder, RequestParamsBuilder};
+
+pub const MAX_FRAMES: usize = 1000; // limited to prevent dos
+
+pub const MIN_DELAY: u16 = 2; // viewers play shorter delays at their own speed
+
+const MAX_DELAY: u16 = 6000; // one minute
+
+const MAX_ANNOTATIONS: usize = 64; // per frame
+
+const MAX_TRANSITION_FRAMES: u8 = 8; // dithered frames grow the output quickly
+
+const MAX_DENSITY: u8 = 2; // larger images are costly to encode
+
+pub const MAX_MOVE_TRAIL: u8 = 4; // each older move needs colors of its own
+
+#[derive(Debug)]
+pub enum ApiError {
+    IllegalFen(PositionErrorKinds),
+    NoFrames,
+    TooManyFrames(usize),
+    BadDelay(u16),
+    BadDelayMs(u32),
+    TooManyAnnotations(usize),
+    TooManyTransitionFrames(u8),
+    MoveTrailTooLong(u8),
+    ConflictingCoordinates,
+    BadDensity(u8),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::IllegalFen(kinds) => write!(f, "illegal fen: {:?}", kinds),
+            ApiError::NoFrames => f.write_str("no frames"),
+            ApiError::TooManyFrames(n) => {
+                write!(f, "too many frames: {} (max {})", n, MAX_FRAMES)
+            }
+            ApiError::BadDelay(delay) => write!(
+                f,
+                "bad delay: {} (min {}, max {})",
+                delay, MIN_DELAY, MAX_DELAY
+            ),
+            ApiError::BadDelayMs(delay) => write!(
+                f,
+                "bad delay: {}ms (min {}ms, max {}ms)",
+                delay,
+                u32::from(MIN_DELAY) * 10,
+                u32::from(MAX_DELAY) * 10
+            ),
+            ApiError::TooManyAnnotations(n) => {
+                write!(f, "too many annotations: {} (max {})", n, MAX_ANNOTATIONS)
+            }
+            ApiError::TooManyTransitionFrames(n) => write!(
+                f,
+                "too many transition frames: {} (max {})",
+                n, MAX_TRANSITION_FRAMES
+            ),
+            ApiError::MoveTrailTooLong(n) => {
+                write!(f, "move trail too long: {} (max {})", n, MAX_MOVE_TRAIL)
+            }
+            ApiError::ConflictingCoordinates => {
+                f.write_str("coordinates and coordinateMargin are mutually exclusive")
+            }
+            ApiError::BadDensity(density) => {
+                write!(f, "bad density: {} (min 1, max {})", density, MAX_DENSITY)
+            }
+        }
+    }
+}
+
+impl error::Error for ApiError {}
+
+/// Checks that the board is a plausible chess position. Material, check
+/// and castling rights are not required to be reachable, but there must be
+/// exactly one king per side and no pawns on the backrank.
+fn validate_fen(fen: &Fen) -> Result<(), ApiError> {
+    Chess::from_setup(fen.0.clone(), CastlingMode::Chess960)
+        .or_else(PositionError::ignore_invalid_castling_rights)
+        .or_else(PositionError::ignore_invalid_ep_square)
+        .or_else(PositionError::ignore_impossible_material)
+        .or_else(PositionError::ignore_impossible_check)
+        .map(drop)
+        .map_err(|err| ApiError::IllegalFen(err.kinds()))
+}
+
+fn validate_coordinates(coordinates: bool, coordinate_margin: bool) -> Result<(), ApiError> {
+    if coordinates && coordinate_margin {
+        Err(ApiError::ConflictingCoordinates)
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_density(density: Option<u8>) -> Result<(), ApiError> {
+    match density {
+        Some(density) if !(1..=MAX_DENSITY).contains(&density) => {
+            Err(ApiError::BadDensity(density))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_delay(delay: u16) -> Result<(), ApiError> {
+    if !(MIN_DELAY..=MAX_DELAY).contains(&delay) {
+        Err(ApiError::BadDelay(delay))
+    } else {
+        Ok(())
+    }
 }
 
-impl Default for Orientation {
-    fn default() -> Orientation {
-        Orientation::White
+fn validate_delay_ms(delay: u32) -> Result<(), ApiError> {
+    if !(u32::from(MIN_DELAY) * 10..=u32::from(MAX_DELAY) * 10).contains(&delay) {
+        Err(ApiError::BadDelayMs(delay))
+    } else {
+        Ok(())
     }
 }
 
+#[derive(Deserialize, PartialEq, Eq, Hash, Copy, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Orientation {
+    #[default]
+    White,
+    Black,
+}
+
 impl Orientation {
     pub fn fold<T>(self, white: T, black: T) -> T {
         match self {
@@ -36,23 +154,306 @@ impl Orientation {
     pub fn y(self, square: Square) -> usize {
         self.fold(7 - usize::from(square.rank()), usize::from(square.rank()))
     }
+
+    /// Gets the orientation that shows the given side at the bottom.
+    pub fn from_color(color: Color) -> Orientation {
+        color.fold_wb(Orientation::White, Orientation::Black)
+    }
+}
+
+/// Chess variant of the rendered positions. Crazyhouse adds pockets
+/// between the board and the player bars.
+#[derive(Deserialize, PartialEq, Eq, Hash, Copy, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Variant {
+    #[default]
+    Standard,
+    Crazyhouse,
+}
+
+/// Colors of the board. High contrast keeps the same sprites, but with
+/// lighter light squares and darker dark squares. Wood adds a grain to the
+/// squares.
+#[derive(Deserialize, PartialEq, Eq, Hash, Copy, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BoardTheme {
+    #[default]
+    Brown,
+    HighContrast,
+    Wood,
+}
+
+impl BoardTheme {
+    pub const ALL: [BoardTheme; 3] = [
+        BoardTheme::Brown,
+        BoardTheme::HighContrast,
+        BoardTheme::Wood,
+    ];
+
+    /// Name as accepted by the `theme` parameter.
+    pub fn name(self) -> &'static str {
+        match self {
+            BoardTheme::Brown => "brown",
+            BoardTheme::HighContrast => "highcontrast",
+            BoardTheme::Wood => "wood",
+        }
+    }
+}
+
+/// Colors of the player bars and other areas around the board: light
+/// text on dark bars, or dark text on light bars.
+#[derive(Deserialize, PartialEq, Eq, Hash, Copy, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BarStyle {
+    #[default]
+    Dark,
+    Light,
+}
+
+/// What happens to the area of a GIF frame before the next one is drawn.
+#[derive(Deserialize, PartialEq, Eq, Copy, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Disposal {
+    /// Leave the frame in place, so that the next frame only needs to
+    /// draw changes.
+    #[default]
+    Keep,
+    /// Clear the area to the background.
+    Background,
+    /// Restore the area to how it was before the frame.
+    Previous,
 }
 
 pub type PlayerName = ArrayString<100>; // length limited to prevent dos
 
+pub type Watermark = ArrayString<64>; // short enough for a corner of the board
+
+pub type Caption = ArrayString<140>; // about one line below the board
+
 pub type Comment = ArrayString<255>; // strict length limit for gif comments
 
-#[derive(Copy, Clone)]
+pub type MetaValue = ArrayString<100>; // length limited to prevent dos
+
+/// Result of a finished game, in PGN notation.
+#[derive(Deserialize, Copy, Clone, PartialEq, Eq)]
+pub enum GameResult {
+    #[serde(rename = "1-0")]
+    WhiteWins,
+    #[serde(rename = "0-1")]
+    BlackWins,
+    #[serde(rename = "1/2-1/2", alias = "½-½")]
+    Draw,
+}
+
+/// Game metadata, embedded as `key=value` lines in an additional comment.
+#[derive(Deserialize, Default)]
+pub struct GameMeta {
+    pub white: Option<PlayerName>,
+    pub black: Option<PlayerName>,
+    pub result: Option<MetaValue>,
+    pub event: Option<MetaValue>,
+    pub date: Option<MetaValue>,
+}
+
+impl fmt::Display for GameMeta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = [
+            ("white", &self.white),
+            ("black", &self.black),
+            ("result", &self.result),
+            ("event", &self.event),
+            ("date", &self.date),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                // Keep one entry per line.
+                let value = value.replace(|c: char| c.is_control(), " ");
+                writeln!(f, "{}={}", key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Color in hexadecimal notation, like `9bc700`, with an optional `#`.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Rgb(pub [u8; 3]);
+
+#[derive(Debug)]
+pub struct ParseRgbError;
+
+impl fmt::Display for ParseRgbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid hex color")
+    }
+}
+
+impl error::Error for ParseRgbError {}
+
+impl FromStr for Rgb {
+    type Err = ParseRgbError;
+
+    fn from_str(s: &str) -> Result<Rgb, ParseRgbError> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 || !hex.is_ascii() {
+            return Err(ParseRgbError);
+        }
+        let mut rgb = [0; 3];
+        for (i, c) in rgb.iter_mut().enumerate() {
+            *c = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| ParseRgbError)?;
+        }
+        Ok(Rgb(rgb))
+    }
+}
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}{:02x}{:02x}", self.0[0], self.0[1], self.0[2])
+    }
+}
+
+/// Rectangle of squares between two corners, like `c2f6`.
+#[derive(Copy, Clone, Hash)]
+pub struct Crop {
+    pub from: Square,
+    pub to: Square,
+}
+
+#[derive(Debug)]
+pub struct ParseCropError;
+
+impl fmt::Display for ParseCropError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid crop, expected two corner squares")
+    }
+}
+
+impl error::Error for ParseCropError {}
+
+impl FromStr for Crop {
+    type Err = ParseCropError;
+
+    fn from_str(s: &str) -> Result<Crop, ParseCropError> {
+        if s.len() != 4 || !s.is_ascii() {
+            return Err(ParseCropError);
+        }
+        Ok(Crop {
+            from: s[..2].parse().map_err(|_| ParseCropError)?,
+            to: s[2..].parse().map_err(|_| ParseCropError)?,
+        })
+    }
+}
+
+impl fmt::Display for Crop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.from, self.to)
+    }
+}
+
+/// Candidate move arrow with a weight relative to the other arrows, like
+/// `e2e4:60`. The weight defaults to 1.
+#[derive(Copy, Clone, Hash)]
+pub struct WeightedArrow {
+    pub from: Square,
+    pub to: Square,
+    pub weight: u16,
+}
+
+#[derive(Debug)]
+pub struct ParseWeightedArrowError;
+
+impl fmt::Display for ParseWeightedArrowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid arrow, expected two squares and an optional weight")
+    }
+}
+
+impl error::Error for ParseWeightedArrowError {}
+
+impl FromStr for WeightedArrow {
+    type Err = ParseWeightedArrowError;
+
+    fn from_str(s: &str) -> Result<WeightedArrow, ParseWeightedArrowError> {
+        let (squares, weight) = match s.split_once(':') {
+            Some((squares, weight)) => (
+                squares,
+                weight.parse().map_err(|_| ParseWeightedArrowError)?,
+            ),
+            None => (s, 1),
+        };
+        if squares.len() != 4 || !squares.is_ascii() {
+            return Err(ParseWeightedArrowError);
+        }
+        Ok(WeightedArrow {
+            from: squares[..2].parse().map_err(|_| ParseWeightedArrowError)?,
+            to: squares[2..].parse().map_err(|_| ParseWeightedArrowError)?,
+            weight,
+        })
+    }
+}
+
+impl fmt::Display for WeightedArrow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}:{}", self.from, self.to, self.weight)
+    }
+}
+
+/// Annotation colors, matching the board brushes on lichess.
+#[derive(Deserialize, Copy, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Brush {
+    #[default]
+    Green,
+    Red,
+    Blue,
+    Yellow,
+}
+
+impl Brush {
+    pub const ALL: [Brush; 4] = [Brush::Green, Brush::Red, Brush::Blue, Brush::Yellow];
+
+    pub fn rgb(self) -> Rgb {
+        Rgb(match self {
+            Brush::Green => [0x15, 0x78, 0x1b],
+            Brush::Red => [0x88, 0x20, 0x20],
+            Brush::Blue => [0x00, 0x30, 0x88],
+            Brush::Yellow => [0xe6, 0x8f, 0x00],
+        })
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, Copy, Clone)]
+#[serde(untagged)]
+pub enum Annotation {
+    Arrow {
+        #[serde_as(as = "DisplayFromStr")]
+        from: Square,
+        #[serde_as(as = "DisplayFromStr")]
+        to: Square,
+        #[serde(default)]
+        color: Brush,
+    },
+    Circle {
+        #[serde_as(as = "DisplayFromStr")]
+        square: Square,
+        #[serde(default)]
+        color: Brush,
+    },
+}
+
+#[derive(Copy, Clone, Default, Hash)]
 pub enum CheckSquare {
+    #[default]
     No,
+    /// The king of the side to move, found in the position.
     Yes,
+    /// The king of the side to move, if it is attacked in the position.
+    Auto,
     Square(Square),
-}
-
-impl Default for CheckSquare {
-    fn default() -> CheckSquare {
-        CheckSquare::No
-    }
+    /// Squares known in advance, used as given. Only kings are drawn in
+    /// check.
+    Squares(Bitboard),
 }
 
 impl<'de> Deserialize<'de> for CheckSquare {
@@ -66,7 +467,7 @@ impl<'de> Deserialize<'de> for CheckSquare {
             type Value = CheckSquare;
 
             fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-                fmt.write_str("square name or bool")
+                fmt.write_str("square name, bool or auto")
             }
 
             fn visit_str<E>(self, name: &str) -> Result<CheckSquare, E>
@@ -77,6 +478,8 @@ impl<'de> Deserialize<'de> for CheckSquare {
                     Ok(CheckSquare::Yes)
                 } else if name == "0" || name == "no" || name == "false" {
                     Ok(CheckSquare::No)
+                } else if name == "auto" {
+                    Ok(CheckSquare::Auto)
                 } else {
                     match name.parse() {
                         Ok(sq) => Ok(CheckSquare::Square(sq)),
@@ -100,22 +503,63 @@ impl<'de> Deserialize<'de> for CheckSquare {
     }
 }
 
+/// Deserializes a bitboard from its integer value, in decimal or in
+/// hexadecimal with a `0x` prefix.
+fn deserialize_bitboard<'de, D>(deserializer: D) -> Result<Option<Bitboard>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map(|bb| Some(Bitboard(bb)))
+    .map_err(|_| de::Error::custom("invalid bitboard"))
+}
+
 impl CheckSquare {
+    /// Gets the checked square. For `CheckSquare::Squares`, that is the
+    /// first of them.
     pub fn to_square(self, setup: &Setup) -> Option<Square> {
         match self {
             CheckSquare::No => None,
             CheckSquare::Yes => setup.board.king_of(setup.turn),
+            CheckSquare::Auto => setup.board.king_of(setup.turn).filter(|&king| {
+                setup
+                    .board
+                    .attacks_to(king, !setup.turn, setup.board.occupied())
+                    .any()
+            }),
             CheckSquare::Square(sq) => Some(sq),
+            CheckSquare::Squares(squares) => squares.first(),
+        }
+    }
+
+    pub fn to_bitboard(self, setup: &Setup) -> Bitboard {
+        match self {
+            CheckSquare::Squares(squares) => squares,
+            check => check.to_square(setup).into_iter().collect(),
         }
     }
 }
 
 #[serde_as]
-#[derive(Deserialize)]
+#[derive(Deserialize, Default, Hash)]
 pub struct RequestParams {
     pub white: Option<PlayerName>,
     pub black: Option<PlayerName>,
+    #[serde(default, rename = "whiteRating")]
+    pub white_rating: Option<u16>,
+    #[serde(default, rename = "blackRating")]
+    pub black_rating: Option<u16>,
     pub comment: Option<Comment>,
+    /// Replace characters outside of ASCII in GIF comments.
+    #[serde(default, rename = "asciiComments")]
+    pub ascii_comments: bool,
+    /// Embed the FEN as a line of the metadata comment.
+    #[serde(default, rename = "embedFen")]
+    pub embed_fen: bool,
     #[serde_as(as = "DisplayFromStr")]
     #[serde(default)]
     pub fen: Fen,
@@ -125,19 +569,218 @@ pub struct RequestParams {
     #[serde(default)]
     pub check: CheckSquare,
     #[serde(default)]
+    pub mate: bool,
+    #[serde(default, rename = "showCheckRay")]
+    pub show_check_ray: bool,
+    /// Square of a piece whose legal moves are marked.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub selected: Option<Square>,
+    /// Candidate moves, drawn wider and more opaque the higher their weight.
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, WeightedArrow>")]
+    #[serde(default)]
+    pub arrows: Vec<WeightedArrow>,
+    /// Arbitrary squares to tint, like an attack map.
+    #[serde(default, deserialize_with = "deserialize_bitboard")]
+    pub overlay: Option<Bitboard>,
+    #[serde(default)]
     pub orientation: Orientation,
+    #[serde(default, rename = "autoOrient")]
+    pub auto_orient: bool,
+    /// Reverse the files, but keep the ranks.
+    #[serde(default)]
+    pub mirror: bool,
+    /// Thin lines between the squares.
+    #[serde(default)]
+    pub grid: bool,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, rename = "secondFen")]
+    pub second_fen: Option<Fen>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, rename = "secondLastMove")]
+    pub second_last_move: Option<Uci>,
+    #[serde(default, rename = "secondCheck")]
+    pub second_check: CheckSquare,
+    #[serde(default, rename = "secondOrientation")]
+    pub second_orientation: Option<Orientation>,
+    #[serde(default)]
+    pub coordinates: bool,
+    #[serde(default, rename = "coordinateMargin")]
+    pub coordinate_margin: bool,
+    #[serde(default)]
+    pub material: bool,
+    #[serde(default, rename = "turnIndicator")]
+    pub turn_indicator: bool,
+    #[serde(default)]
+    pub shadows: bool,
+    #[serde(default)]
+    pub blindfold: bool,
+    #[serde(default, rename = "rotateOpponentPieces")]
+    pub rotate_opponent_pieces: bool,
+    #[serde(default)]
+    pub size: Option<u16>,
+    /// Pixels per pixel of the requested size, for high-DPI displays.
+    #[serde(default)]
+    pub density: Option<u8>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub highlight: Option<Rgb>,
+    #[serde(default, rename = "lastMoveArrow")]
+    pub last_move_arrow: bool,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub crop: Option<Crop>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub background: Option<Rgb>,
+    #[serde(default, rename = "transparentBackground")]
+    pub transparent_background: bool,
+    #[serde(default)]
+    pub interlace: bool,
+    #[serde(default)]
+    pub variant: Variant,
+    #[serde(default)]
+    pub grayscale: bool,
+    #[serde(default)]
+    pub theme: BoardTheme,
+    #[serde(default, rename = "barStyle")]
+    pub bar_style: BarStyle,
+    #[serde(default)]
+    pub watermark: Option<Watermark>,
+}
+
+impl RequestParams {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        validate_fen(&self.fen)?;
+        if let Some(ref fen) = self.second_fen {
+            validate_fen(fen)?;
+        }
+        validate_coordinates(self.coordinates, self.coordinate_margin)?;
+        validate_density(self.density)?;
+        if self.arrows.len() > MAX_ANNOTATIONS {
+            return Err(ApiError::TooManyAnnotations(self.arrows.len()));
+        }
+        Ok(())
+    }
 }
 
-#[derive(Deserialize)]
+#[serde_as]
+#[derive(Deserialize, Default)]
 pub struct RequestBody {
     pub white: Option<PlayerName>,
     pub black: Option<PlayerName>,
+    #[serde(default, rename = "whiteRating")]
+    pub white_rating: Option<u16>,
+    #[serde(default, rename = "blackRating")]
+    pub black_rating: Option<u16>,
     pub comment: Option<Comment>,
+    /// Replace characters outside of ASCII in GIF comments.
+    #[serde(default, rename = "asciiComments")]
+    pub ascii_comments: bool,
     pub frames: Vec<RequestFrame>,
+    /// Position of an additional first frame, shown where animations do
+    /// not play. Animations show it for the shortest valid delay, before
+    /// cutting to the actual first frame.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub poster: Option<Fen>,
     #[serde(default)]
     pub orientation: Orientation,
+    #[serde(default, rename = "autoOrient")]
+    pub auto_orient: bool,
+    /// Reverse the files, but keep the ranks.
+    #[serde(default)]
+    pub mirror: bool,
+    /// Thin lines between the squares.
+    #[serde(default)]
+    pub grid: bool,
+    #[serde(default)]
+    pub coordinates: bool,
+    #[serde(default, rename = "coordinateMargin")]
+    pub coordinate_margin: bool,
+    #[serde(default)]
+    pub material: bool,
+    #[serde(default, rename = "turnIndicator")]
+    pub turn_indicator: bool,
+    #[serde(default)]
+    pub shadows: bool,
+    #[serde(default)]
+    pub blindfold: bool,
+    #[serde(default, rename = "rotateOpponentPieces")]
+    pub rotate_opponent_pieces: bool,
+    #[serde(default)]
+    pub size: Option<u16>,
+    /// Pixels per pixel of the requested size, for high-DPI displays.
+    #[serde(default)]
+    pub density: Option<u8>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub highlight: Option<Rgb>,
+    #[serde(default, rename = "lastMoveArrow")]
+    pub last_move_arrow: bool,
+    #[serde(default, rename = "showCheckRay")]
+    pub show_check_ray: bool,
+    #[serde(default, rename = "previousMove")]
+    pub previous_move: bool,
+    #[serde(default, rename = "moveTrail")]
+    pub move_trail: u8,
     #[serde(default)]
     pub delay: u16,
+    #[serde(default, rename = "delayMs")]
+    pub delay_ms: Option<u32>,
+    /// Delay of the last frame in centiseconds, overriding its own.
+    #[serde(default, rename = "finalDelay")]
+    pub final_delay: Option<u16>,
+    #[serde(default, rename = "transitionFrames")]
+    pub transition_frames: u8,
+    #[serde(default, rename = "flipTransition")]
+    pub flip_transition: bool,
+    #[serde(default, rename = "promotionOverlay")]
+    pub promotion_overlay: bool,
+    #[serde(default, rename = "moveList")]
+    pub move_list: bool,
+    #[serde(default, rename = "splitRegions")]
+    pub split_regions: bool,
+    #[serde(default)]
+    pub interlace: bool,
+    #[serde(default)]
+    pub disposal: Disposal,
+    #[serde(default)]
+    pub bounce: bool,
+    #[serde(default, rename = "endFrame")]
+    pub end_frame: Option<bool>,
+    #[serde(default)]
+    pub loops: Option<NonZeroU16>,
+    #[serde(default)]
+    pub metadata: Option<GameMeta>,
+    /// Shown in a banner over the last frame.
+    #[serde(default)]
+    pub result: Option<GameResult>,
+    #[serde(default)]
+    pub variant: Variant,
+    #[serde(default)]
+    pub grayscale: bool,
+    #[serde(default)]
+    pub theme: BoardTheme,
+    #[serde(default, rename = "barStyle")]
+    pub bar_style: BarStyle,
+    #[serde(default)]
+    pub watermark: Option<Watermark>,
+}
+
+/// Engine evaluation from the point of view of white.
+#[derive(Deserialize, PartialEq, Eq, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Eval {
+    Cp(i32),
+    Mate(i32),
+}
+
+/// Remaining clock times in centiseconds.
+#[derive(Deserialize, PartialEq, Eq, Copy, Clone)]
+pub struct Clock {
+    pub white: u32,
+    pub black: u32,
 }
 
 #[serde_as]
@@ -148,14 +791,91 @@ pub struct RequestFrame {
     pub fen: Fen,
     #[serde(default)]
     pub delay: Option<u16>,
+    #[serde(default, rename = "delayMs")]
+    pub delay_ms: Option<u32>,
     #[serde_as(as = "Option<DisplayFromStr>")]
     #[serde(default, rename = "lastMove")]
     pub last_move: Option<Uci>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub premove: Option<Uci>,
     #[serde(default)]
     pub check: CheckSquare,
+    #[serde(default)]
+    pub mate: bool,
+    /// Square of a piece whose legal moves are marked.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub selected: Option<Square>,
+    #[serde(default)]
+    pub clock: Option<Clock>,
+    #[serde(default)]
+    pub eval: Option<Eval>,
+    #[serde(default)]
+    pub annotations: Vec<Annotation>,
+    #[serde(default)]
+    pub orientation: Option<Orientation>,
+    /// Shown in a strip below the board.
+    #[serde(default)]
+    pub caption: Option<Caption>,
 }
 
 impl RequestBody {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        if self.frames.is_empty() {
+            return Err(ApiError::NoFrames);
+        }
+        if self.frames.len() > MAX_FRAMES {
+            return Err(ApiError::TooManyFrames(self.frames.len()));
+        }
+        // Zero is the value when omitted, see `default_delay_ms()`.
+        if self.delay != 0 {
+            validate_delay(self.delay)?;
+        }
+        if let Some(delay) = self.delay_ms {
+            validate_delay_ms(delay)?;
+        }
+        if let Some(delay) = self.final_delay {
+            validate_delay(delay)?;
+        }
+        if self.transition_frames > MAX_TRANSITION_FRAMES {
+            return Err(ApiError::TooManyTransitionFrames(self.transition_frames));
+        }
+        if self.move_trail > MAX_MOVE_TRAIL {
+            return Err(ApiError::MoveTrailTooLong(self.move_trail));
+        }
+        validate_coordinates(self.coordinates, self.coordinate_margin)?;
+        validate_density(self.density)?;
+        if let Some(ref fen) = self.poster {
+            validate_fen(fen)?;
+        }
+        for frame in &self.frames {
+            validate_fen(&frame.fen)?;
+            if let Some(delay) = frame.delay {
+                validate_delay(delay)?;
+            }
+            if let Some(delay) = frame.delay_ms {
+                validate_delay_ms(delay)?;
+            }
+            if frame.annotations.len() > MAX_ANNOTATIONS {
+                return Err(ApiError::TooManyAnnotations(frame.annotations.len()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Delay in milliseconds of frames without a delay of their own. If
+    /// neither `delay` nor `delayMs` is given, that is the shortest valid
+    /// delay, rather than a delay of 0, which viewers replace with one of
+    /// their own choosing.
+    pub fn default_delay_ms(&self) -> u32 {
+        match (self.delay_ms, self.delay) {
+            (Some(delay), _) => delay,
+            (None, 0) => u32::from(MIN_DELAY) * 10,
+            (None, delay) => u32::from(delay) * 10,
+        }
+    }
+
     pub fn example() -> RequestBody {
         let pgn = "\
             1. c4 Nf6 2. Nc3 e5 3. d4 exd4 4. Qxd4 Nc6 5. Qd1 Bb4 6. Bd2 O-O \
@@ -168,40 +888,140 @@ impl RequestBody {
             38. Ng2 hxg4 39. fxg4 Nd8 40. Nf4+ Kf7 41. h5 g5 42. Ne2 Ne6 \
             43. Kf3 Kg7 44. Ke3 Kh6 45. Ng3 Ng7 46. Nf5+ Nxf5+";
 
-        let mut frames = Vec::with_capacity(46 * 2 + 1);
-        frames.push(RequestFrame::default());
+        let mut frames = pgn::frames(pgn).expect("valid example");
+        frames.last_mut().unwrap().delay = Some(500);
 
-        let mut pos = Chess::default();
-        for pgn_move in pgn.split(' ') {
-            if pgn_move.trim().is_empty() || pgn_move.ends_with('.') {
-                continue;
-            }
+        RequestBody::builder()
+            .comment(Comment::from("https://lichess.org/Q0iQs5Zi").unwrap())
+            .white(PlayerName::from("GM DrDrunkenstein").unwrap())
+            .black(PlayerName::from("GM Zhigalko_Sergei").unwrap())
+            .white_rating(2888)
+            .black_rating(2895)
+            .delay(50)
+            .frames(frames)
+            .build()
+            .expect("valid example")
+    }
+}
 
-            let san: San = pgn_move.parse().unwrap();
-            let m = san.to_move(&pos).unwrap();
-            pos.play_unchecked(&m);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            frames.push(RequestFrame {
-                fen: Fen(pos.clone().into_setup(EnPassantMode::Always)),
-                check: if pos.is_check() {
-                    CheckSquare::Yes
-                } else {
-                    CheckSquare::No
-                },
-                last_move: Some(Uci::from_move(&m, CastlingMode::Standard)),
-                delay: None,
-            })
-        }
+    fn setup(fen: &str) -> Setup {
+        fen.parse::<Fen>().expect("valid fen").0
+    }
 
-        frames.last_mut().unwrap().delay = Some(500);
+    #[test]
+    fn check_auto() {
+        // Black is in check from the bishop on b5.
+        let check = setup("rnbqkbnr/ppp2ppp/3p4/1B2p3/4P3/8/PPPP1PPP/RNBQK1NR b KQkq - 1 3");
+        assert_eq!(CheckSquare::Auto.to_square(&check), Some(Square::E8));
+        assert_eq!(
+            CheckSquare::Auto.to_bitboard(&check),
+            Bitboard::from(Square::E8)
+        );
 
+        let quiet = setup("rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2");
+        assert_eq!(CheckSquare::Auto.to_square(&quiet), None);
+        assert_eq!(CheckSquare::Auto.to_bitboard(&quiet), Bitboard::EMPTY);
+        // Unlike auto, yes marks the king regardless.
+        assert_eq!(CheckSquare::Yes.to_square(&quiet), Some(Square::E1));
+    }
+
+    #[test]
+    fn check_explicit_square_overrides() {
+        let quiet = setup("rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2");
+        let params: RequestParams = serde_urlencoded::from_str("check=e8").unwrap();
+        assert_eq!(params.check.to_square(&quiet), Some(Square::E8));
+        let params: RequestParams = serde_urlencoded::from_str("check=auto").unwrap();
+        assert!(matches!(params.check, CheckSquare::Auto));
+    }
+
+    fn body(delay: u16, delay_ms: Option<u32>, frame_delay: Option<u16>) -> RequestBody {
         RequestBody {
-            comment: Some(Comment::from("https://lichess.org/Q0iQs5Zi").unwrap()),
-            white: Some(PlayerName::from("GM DrDrunkenstein (2888)").unwrap()),
-            black: Some(PlayerName::from("GM Zhigalko_Sergei (2895)").unwrap()),
-            orientation: Orientation::White,
-            delay: 50,
-            frames,
+            delay,
+            delay_ms,
+            frames: vec![RequestFrame {
+                delay: frame_delay,
+                ..RequestFrame::default()
+            }],
+            ..RequestBody::default()
+        }
+    }
+
+    #[test]
+    fn delay_bounds() {
+        for (delay, valid) in [(1, false), (2, true), (6000, true), (6001, false)] {
+            assert_eq!(
+                body(delay, None, None).validate().is_ok(),
+                valid,
+                "{}",
+                delay
+            );
+            assert_eq!(
+                body(50, None, Some(delay)).validate().is_ok(),
+                valid,
+                "frame {}",
+                delay
+            );
+            assert_eq!(
+                RequestBody {
+                    final_delay: Some(delay),
+                    ..body(50, None, None)
+                }
+                .validate()
+                .is_ok(),
+                valid,
+                "final {}",
+                delay
+            );
         }
+        assert!(matches!(
+            body(1, None, None).validate(),
+            Err(ApiError::BadDelay(1))
+        ));
+    }
+
+    #[test]
+    fn delay_ms_bounds() {
+        for (delay, valid) in [(19, false), (20, true), (60000, true), (60001, false)] {
+            assert_eq!(
+                body(0, Some(delay), None).validate().is_ok(),
+                valid,
+                "{}",
+                delay
+            );
+        }
+        assert!(matches!(
+            body(0, Some(1), None).validate(),
+            Err(ApiError::BadDelayMs(1))
+        ));
+    }
+
+    #[test]
+    fn omitted_delay_is_shortest() {
+        let body: RequestBody =
+            serde_json::from_str(r#"{ "frames": [{ "fen": "4k3/8/8/8/8/8/8/4K3 w - - 0 1" }] }"#)
+                .unwrap();
+        assert!(body.validate().is_ok());
+        assert_eq!(body.default_delay_ms(), u32::from(MIN_DELAY) * 10);
+        assert_eq!(
+            RequestBody {
+                delay: 50,
+                ..RequestBody::default()
+            }
+            .default_delay_ms(),
+            500
+        );
+        assert_eq!(
+            RequestBody {
+                delay: 50,
+                delay_ms: Some(333),
+                ..RequestBody::default()
+            }
+            .default_delay_ms(),
+            333
+        );
     }
 }
@@ -0,0 +1,722 @@
+//! Builders for requests constructed in Rust rather than deserialized.
+//!
+//! Required fields are tracked in the type, so that `build()` is only
+//! available once they are set. Everything else starts out with the same
+//! defaults as the HTTP API.
+
+use std::{marker::PhantomData, num::NonZeroU16};
+
+use shakmaty::{fen::Fen, uci::Uci, Bitboard, Square};
+
+use super::{
+    ApiError, BarStyle, BoardTheme, CheckSquare, Comment, Crop, Disposal, GameMeta, GameResult,
+    Orientation, PlayerName, RequestBody, RequestFrame, RequestParams, Rgb, Variant, Watermark,
+    WeightedArrow,
+};
+
+/// Marks a required field that has not been set yet.
+pub struct Missing;
+
+/// Marks a required field that has been set.
+pub struct Present;
+
+pub struct RequestParamsBuilder<F = Missing> {
+    params: RequestParams,
+    fen: PhantomData<F>,
+}
+
+impl RequestParams {
+    pub fn builder() -> RequestParamsBuilder {
+        RequestParamsBuilder {
+            params: RequestParams::default(),
+            fen: PhantomData,
+        }
+    }
+}
+
+impl<F> RequestParamsBuilder<F> {
+    pub fn fen(mut self, fen: Fen) -> RequestParamsBuilder<Present> {
+        self.params.fen = fen;
+        RequestParamsBuilder {
+            params: self.params,
+            fen: PhantomData,
+        }
+    }
+
+    pub fn white(mut self, name: PlayerName) -> Self {
+        self.params.white = Some(name);
+        self
+    }
+
+    pub fn black(mut self, name: PlayerName) -> Self {
+        self.params.black = Some(name);
+        self
+    }
+
+    pub fn white_rating(mut self, rating: u16) -> Self {
+        self.params.white_rating = Some(rating);
+        self
+    }
+
+    pub fn black_rating(mut self, rating: u16) -> Self {
+        self.params.black_rating = Some(rating);
+        self
+    }
+
+    /// Replaces the default link to the repository. Pass an empty comment
+    /// to omit the comment block altogether.
+    pub fn comment(mut self, comment: Comment) -> Self {
+        self.params.comment = Some(comment);
+        self
+    }
+
+    /// Replaces characters outside of 7-bit ASCII with `?` in GIF
+    /// comments, including the metadata, for strict readers.
+    pub fn ascii_comments(mut self, ascii_comments: bool) -> Self {
+        self.params.ascii_comments = ascii_comments;
+        self
+    }
+
+    /// Embeds the FEN as a `fen=` line of the metadata comment, so that
+    /// the position can be recovered from the image.
+    pub fn embed_fen(mut self, embed_fen: bool) -> Self {
+        self.params.embed_fen = embed_fen;
+        self
+    }
+
+    pub fn last_move(mut self, uci: Uci) -> Self {
+        self.params.last_move = Some(uci);
+        self
+    }
+
+    pub fn check(mut self, check: CheckSquare) -> Self {
+        self.params.check = check;
+        self
+    }
+
+    /// Marks the king in check as checkmated.
+    pub fn mate(mut self, mate: bool) -> Self {
+        self.params.mate = mate;
+        self
+    }
+
+    /// Faintly marks the squares between a checking slider and the king.
+    pub fn show_check_ray(mut self, show_check_ray: bool) -> Self {
+        self.params.show_check_ray = show_check_ray;
+        self
+    }
+
+    /// Marks the legal moves of the piece on `square`, with dots on empty
+    /// squares and rings around captures.
+    pub fn selected(mut self, square: Square) -> Self {
+        self.params.selected = Some(square);
+        self
+    }
+
+    /// Adds a candidate move arrow, sized and shaded by its weight relative
+    /// to the other arrows.
+    pub fn arrow(mut self, from: Square, to: Square, weight: u16) -> Self {
+        self.params.arrows.push(WeightedArrow { from, to, weight });
+        self
+    }
+
+    /// Tints arbitrary squares, like an attack map or a pawn structure.
+    pub fn overlay(mut self, overlay: Bitboard) -> Self {
+        self.params.overlay = Some(overlay);
+        self
+    }
+
+    pub fn orientation(mut self, orientation: Orientation) -> Self {
+        self.params.orientation = orientation;
+        self
+    }
+
+    pub fn auto_orient(mut self, auto_orient: bool) -> Self {
+        self.params.auto_orient = auto_orient;
+        self
+    }
+
+    /// Mirrors the board horizontally: files are reversed, while ranks
+    /// stay as they are with the orientation.
+    pub fn mirror(mut self, mirror: bool) -> Self {
+        self.params.mirror = mirror;
+        self
+    }
+
+    /// Draws thin lines between the squares.
+    pub fn grid(mut self, grid: bool) -> Self {
+        self.params.grid = grid;
+        self
+    }
+
+    /// Renders a second position to the right of the first.
+    pub fn second_fen(mut self, fen: Fen) -> Self {
+        self.params.second_fen = Some(fen);
+        self
+    }
+
+    pub fn second_last_move(mut self, uci: Uci) -> Self {
+        self.params.second_last_move = Some(uci);
+        self
+    }
+
+    pub fn second_check(mut self, check: CheckSquare) -> Self {
+        self.params.second_check = check;
+        self
+    }
+
+    pub fn second_orientation(mut self, orientation: Orientation) -> Self {
+        self.params.second_orientation = Some(orientation);
+        self
+    }
+
+    pub fn coordinates(mut self, coordinates: bool) -> Self {
+        self.params.coordinates = coordinates;
+        self
+    }
+
+    /// Labels ranks and files in a margin left of and below the board,
+    /// instead of on the edge squares like `coordinates()`. The two are
+    /// mutually exclusive.
+    pub fn coordinate_margin(mut self, coordinate_margin: bool) -> Self {
+        self.params.coordinate_margin = coordinate_margin;
+        self
+    }
+
+    pub fn material(mut self, material: bool) -> Self {
+        self.params.material = material;
+        self
+    }
+
+    /// Marks the side to move with a dot in its player bar.
+    pub fn turn_indicator(mut self, turn_indicator: bool) -> Self {
+        self.params.turn_indicator = turn_indicator;
+        self
+    }
+
+    /// Draws subtle drop shadows below the pieces.
+    pub fn shadows(mut self, shadows: bool) -> Self {
+        self.params.shadows = shadows;
+        self
+    }
+
+    pub fn blindfold(mut self, blindfold: bool) -> Self {
+        self.params.blindfold = blindfold;
+        self
+    }
+
+    /// Turns the pieces of the side at the top of the board upside down,
+    /// for a player sitting on the other side of the screen.
+    pub fn rotate_opponent_pieces(mut self, rotate_opponent_pieces: bool) -> Self {
+        self.params.rotate_opponent_pieces = rotate_opponent_pieces;
+        self
+    }
+
+    pub fn size(mut self, size: u16) -> Self {
+        self.params.size = Some(size);
+        self
+    }
+
+    /// Renders `density` times as many pixels in each direction, for
+    /// high-DPI displays.
+    pub fn density(mut self, density: u8) -> Self {
+        self.params.density = Some(density);
+        self
+    }
+
+    pub fn highlight(mut self, color: Rgb) -> Self {
+        self.params.highlight = Some(color);
+        self
+    }
+
+    pub fn last_move_arrow(mut self, last_move_arrow: bool) -> Self {
+        self.params.last_move_arrow = last_move_arrow;
+        self
+    }
+
+    pub fn crop(mut self, crop: Crop) -> Self {
+        self.params.crop = Some(crop);
+        self
+    }
+
+    pub fn background(mut self, color: Rgb) -> Self {
+        self.params.background = Some(color);
+        self
+    }
+
+    /// Leaves the player bars and other areas around the board transparent.
+    /// Only for GIF.
+    pub fn transparent_background(mut self, transparent_background: bool) -> Self {
+        self.params.transparent_background = transparent_background;
+        self
+    }
+
+    /// Interlaces the image, so that a rough version shows early while
+    /// loading. Only for GIF.
+    pub fn interlace(mut self, interlace: bool) -> Self {
+        self.params.interlace = interlace;
+        self
+    }
+
+    pub fn variant(mut self, variant: Variant) -> Self {
+        self.params.variant = variant;
+        self
+    }
+
+    pub fn grayscale(mut self, grayscale: bool) -> Self {
+        self.params.grayscale = grayscale;
+        self
+    }
+
+    pub fn theme(mut self, theme: BoardTheme) -> Self {
+        self.params.theme = theme;
+        self
+    }
+
+    pub fn bar_style(mut self, bar_style: BarStyle) -> Self {
+        self.params.bar_style = bar_style;
+        self
+    }
+
+    pub fn watermark(mut self, text: Watermark) -> Self {
+        self.params.watermark = Some(text);
+        self
+    }
+}
+
+impl RequestParamsBuilder<Present> {
+    /// Validates the request like the HTTP API would.
+    pub fn build(self) -> Result<RequestParams, ApiError> {
+        self.params.validate()?;
+        Ok(self.params)
+    }
+}
+
+pub struct RequestBodyBuilder<F = Missing> {
+    body: RequestBody,
+    frames: PhantomData<F>,
+}
+
+impl RequestBody {
+    pub fn builder() -> RequestBodyBuilder {
+        RequestBodyBuilder {
+            body: RequestBody::default(),
+            frames: PhantomData,
+        }
+    }
+}
+
+impl<F> RequestBodyBuilder<F> {
+    pub fn frame(mut self, frame: RequestFrame) -> RequestBodyBuilder<Present> {
+        self.body.frames.push(frame);
+        RequestBodyBuilder {
+            body: self.body,
+            frames: PhantomData,
+        }
+    }
+
+    pub fn frames<I>(mut self, frames: I) -> RequestBodyBuilder<Present>
+    where
+        I: IntoIterator<Item = RequestFrame>,
+    {
+        self.body.frames.extend(frames);
+        RequestBodyBuilder {
+            body: self.body,
+            frames: PhantomData,
+        }
+    }
+
+    pub fn white(mut self, name: PlayerName) -> Self {
+        self.body.white = Some(name);
+        self
+    }
+
+    pub fn black(mut self, name: PlayerName) -> Self {
+        self.body.black = Some(name);
+        self
+    }
+
+    pub fn white_rating(mut self, rating: u16) -> Self {
+        self.body.white_rating = Some(rating);
+        self
+    }
+
+    pub fn black_rating(mut self, rating: u16) -> Self {
+        self.body.black_rating = Some(rating);
+        self
+    }
+
+    /// Replaces the default link to the repository. Pass an empty comment
+    /// to omit the comment block altogether.
+    pub fn comment(mut self, comment: Comment) -> Self {
+        self.body.comment = Some(comment);
+        self
+    }
+
+    /// Replaces characters outside of 7-bit ASCII with `?` in GIF
+    /// comments, including the metadata, for strict readers.
+    pub fn ascii_comments(mut self, ascii_comments: bool) -> Self {
+        self.body.ascii_comments = ascii_comments;
+        self
+    }
+
+    pub fn orientation(mut self, orientation: Orientation) -> Self {
+        self.body.orientation = orientation;
+        self
+    }
+
+    pub fn auto_orient(mut self, auto_orient: bool) -> Self {
+        self.body.auto_orient = auto_orient;
+        self
+    }
+
+    /// Mirrors the board horizontally: files are reversed, while ranks
+    /// stay as they are with the orientation.
+    pub fn mirror(mut self, mirror: bool) -> Self {
+        self.body.mirror = mirror;
+        self
+    }
+
+    /// Draws thin lines between the squares.
+    pub fn grid(mut self, grid: bool) -> Self {
+        self.body.grid = grid;
+        self
+    }
+
+    pub fn coordinates(mut self, coordinates: bool) -> Self {
+        self.body.coordinates = coordinates;
+        self
+    }
+
+    /// Labels ranks and files in a margin left of and below the board,
+    /// instead of on the edge squares like `coordinates()`. The two are
+    /// mutually exclusive.
+    pub fn coordinate_margin(mut self, coordinate_margin: bool) -> Self {
+        self.body.coordinate_margin = coordinate_margin;
+        self
+    }
+
+    pub fn material(mut self, material: bool) -> Self {
+        self.body.material = material;
+        self
+    }
+
+    /// Marks the side to move with a dot in its player bar.
+    pub fn turn_indicator(mut self, turn_indicator: bool) -> Self {
+        self.body.turn_indicator = turn_indicator;
+        self
+    }
+
+    /// Draws subtle drop shadows below the pieces.
+    pub fn shadows(mut self, shadows: bool) -> Self {
+        self.body.shadows = shadows;
+        self
+    }
+
+    pub fn blindfold(mut self, blindfold: bool) -> Self {
+        self.body.blindfold = blindfold;
+        self
+    }
+
+    /// Turns the pieces of the side at the top of the board upside down,
+    /// for a player sitting on the other side of the screen.
+    pub fn rotate_opponent_pieces(mut self, rotate_opponent_pieces: bool) -> Self {
+        self.body.rotate_opponent_pieces = rotate_opponent_pieces;
+        self
+    }
+
+    pub fn size(mut self, size: u16) -> Self {
+        self.body.size = Some(size);
+        self
+    }
+
+    /// Renders `density` times as many pixels in each direction, for
+    /// high-DPI displays.
+    pub fn density(mut self, density: u8) -> Self {
+        self.body.density = Some(density);
+        self
+    }
+
+    pub fn highlight(mut self, color: Rgb) -> Self {
+        self.body.highlight = Some(color);
+        self
+    }
+
+    pub fn last_move_arrow(mut self, last_move_arrow: bool) -> Self {
+        self.body.last_move_arrow = last_move_arrow;
+        self
+    }
+
+    /// Faintly marks the squares between a checking slider and the king.
+    pub fn show_check_ray(mut self, show_check_ray: bool) -> Self {
+        self.body.show_check_ray = show_check_ray;
+        self
+    }
+
+    pub fn previous_move(mut self, previous_move: bool) -> Self {
+        self.body.previous_move = previous_move;
+        self
+    }
+
+    /// Highlights the last `n` moves, older ones fainter. Overrides
+    /// `previous_move()` if more than one.
+    pub fn move_trail(mut self, n: u8) -> Self {
+        self.body.move_trail = n;
+        self
+    }
+
+    /// Default frame delay in centiseconds.
+    pub fn delay(mut self, delay: u16) -> Self {
+        self.body.delay = delay;
+        self
+    }
+
+    /// Default frame delay in milliseconds, overriding `delay()`.
+    pub fn delay_ms(mut self, delay: u32) -> Self {
+        self.body.delay_ms = Some(delay);
+        self
+    }
+
+    /// Holds the last frame for `delay` centiseconds before the animation
+    /// ends or loops, whatever its own delay.
+    pub fn final_delay(mut self, delay: u16) -> Self {
+        self.body.final_delay = Some(delay);
+        self
+    }
+
+    pub fn transition_frames(mut self, transition_frames: u8) -> Self {
+        self.body.transition_frames = transition_frames;
+        self
+    }
+
+    pub fn flip_transition(mut self, flip_transition: bool) -> Self {
+        self.body.flip_transition = flip_transition;
+        self
+    }
+
+    /// Briefly shows promoted pieces enlarged before continuing.
+    pub fn promotion_overlay(mut self, promotion_overlay: bool) -> Self {
+        self.body.promotion_overlay = promotion_overlay;
+        self
+    }
+
+    pub fn move_list(mut self, move_list: bool) -> Self {
+        self.body.move_list = move_list;
+        self
+    }
+
+    pub fn split_regions(mut self, split_regions: bool) -> Self {
+        self.body.split_regions = split_regions;
+        self
+    }
+
+    /// Interlaces the first frame, which covers the whole image. Only for
+    /// GIF.
+    pub fn interlace(mut self, interlace: bool) -> Self {
+        self.body.interlace = interlace;
+        self
+    }
+
+    /// Disposal of GIF frames. Anything but `Disposal::Keep` draws every
+    /// frame in full.
+    pub fn disposal(mut self, disposal: Disposal) -> Self {
+        self.body.disposal = disposal;
+        self
+    }
+
+    pub fn bounce(mut self, bounce: bool) -> Self {
+        self.body.bounce = bounce;
+        self
+    }
+
+    /// Whether to end with a black frame, so that twitter does not cut off
+    /// the last frame. Defaults to `true`, unless bouncing.
+    pub fn end_frame(mut self, end_frame: bool) -> Self {
+        self.body.end_frame = Some(end_frame);
+        self
+    }
+
+    pub fn loops(mut self, loops: NonZeroU16) -> Self {
+        self.body.loops = Some(loops);
+        self
+    }
+
+    pub fn metadata(mut self, metadata: GameMeta) -> Self {
+        self.body.metadata = Some(metadata);
+        self
+    }
+
+    /// Shows the result in a banner over the center of the last frame.
+    pub fn result(mut self, result: GameResult) -> Self {
+        self.body.result = Some(result);
+        self
+    }
+
+    /// Starts with a frame of another position, like the final one, which
+    /// is what viewers show when animations do not play.
+    pub fn poster(mut self, fen: Fen) -> Self {
+        self.body.poster = Some(fen);
+        self
+    }
+
+    pub fn variant(mut self, variant: Variant) -> Self {
+        self.body.variant = variant;
+        self
+    }
+
+    pub fn grayscale(mut self, grayscale: bool) -> Self {
+        self.body.grayscale = grayscale;
+        self
+    }
+
+    pub fn theme(mut self, theme: BoardTheme) -> Self {
+        self.body.theme = theme;
+        self
+    }
+
+    pub fn bar_style(mut self, bar_style: BarStyle) -> Self {
+        self.body.bar_style = bar_style;
+        self
+    }
+
+    pub fn watermark(mut self, text: Watermark) -> Self {
+        self.body.watermark = Some(text);
+        self
+    }
+}
+
+impl RequestBodyBuilder<Present> {
+    /// Validates the request like the HTTP API would.
+    pub fn build(self) -> Result<RequestBody, ApiError> {
+        self.body.validate()?;
+        Ok(self.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache::ImageCache, render::Format};
+
+    const FEN: &str = "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3";
+
+    fn key(params: &RequestParams) -> u64 {
+        ImageCache::key(params, Format::Gif)
+    }
+
+    #[test]
+    fn params_defaults_match_query() {
+        let built = RequestParams::builder()
+            .fen(Fen::default())
+            .build()
+            .unwrap();
+        let parsed: RequestParams = serde_urlencoded::from_str("").unwrap();
+        assert_eq!(key(&built), key(&parsed));
+    }
+
+    #[test]
+    fn params_round_trip() {
+        let built = RequestParams::builder()
+            .fen(FEN.parse().unwrap())
+            .white(PlayerName::from("Carlsen").unwrap())
+            .black_rating(2800)
+            .comment(Comment::from("study").unwrap())
+            .last_move("g1f3".parse().unwrap())
+            .check(CheckSquare::Auto)
+            .orientation(Orientation::Black)
+            .arrow(Square::F1, Square::B5, 3)
+            .arrow(Square::F1, Square::C4, 1)
+            .theme(BoardTheme::Wood)
+            .build()
+            .unwrap();
+        let parsed: RequestParams = serde_urlencoded::from_str(&format!(
+            "fen={}&white=Carlsen&blackRating=2800&comment=study&lastMove=g1f3&check=auto\
+             &orientation=black&arrows=f1b5:3,f1c4&theme=wood",
+            FEN.replace(' ', "+")
+        ))
+        .unwrap();
+        assert_eq!(key(&built), key(&parsed));
+        assert_ne!(key(&built), key(&RequestParams::default()));
+    }
+
+    #[test]
+    fn params_build_validates() {
+        let err = RequestParams::builder()
+            .fen("8/8/8/8/8/8/8/8 w - - 0 1".parse().unwrap())
+            .build();
+        assert!(matches!(err, Err(ApiError::IllegalFen(_))));
+        let err = RequestParams::builder()
+            .fen(Fen::default())
+            .coordinates(true)
+            .coordinate_margin(true)
+            .build();
+        assert!(matches!(err, Err(ApiError::ConflictingCoordinates)));
+    }
+
+    #[test]
+    fn body_round_trip() {
+        let built = RequestBody::builder()
+            .white(PlayerName::from("Carlsen").unwrap())
+            .black(PlayerName::from("Caruana").unwrap())
+            .orientation(Orientation::Black)
+            .delay(80)
+            .loops(NonZeroU16::new(2).unwrap())
+            .frame(RequestFrame {
+                fen: Fen::default(),
+                ..RequestFrame::default()
+            })
+            .frame(RequestFrame {
+                fen: FEN.parse().unwrap(),
+                last_move: Some("g1f3".parse().unwrap()),
+                delay: Some(300),
+                ..RequestFrame::default()
+            })
+            .build()
+            .unwrap();
+        let parsed: RequestBody = serde_json::from_str(&format!(
+            r#"{{
+                "white": "Carlsen",
+                "black": "Caruana",
+                "orientation": "black",
+                "delay": 80,
+                "loops": 2,
+                "frames": [{{}}, {{ "fen": "{}", "lastMove": "g1f3", "delay": 300 }}]
+            }}"#,
+            FEN
+        ))
+        .unwrap();
+        parsed.validate().unwrap();
+        for body in [&built, &parsed] {
+            assert_eq!(body.white.as_deref(), Some("Carlsen"));
+            assert_eq!(body.black.as_deref(), Some("Caruana"));
+            assert!(body.orientation == Orientation::Black);
+            assert_eq!(body.delay, 80);
+            assert_eq!(body.loops, NonZeroU16::new(2));
+            assert_eq!(body.frames.len(), 2);
+            assert_eq!(body.frames[0].fen, Fen::default());
+            assert_eq!(body.frames[1].fen.to_string(), FEN);
+            assert_eq!(body.frames[1].last_move, Some("g1f3".parse().unwrap()));
+            assert_eq!(body.frames[1].delay, Some(300));
+            assert!(body.comment.is_none());
+            assert!(!body.bounce);
+        }
+    }
+
+    #[test]
+    fn body_build_validates() {
+        let frame = || RequestFrame {
+            fen: Fen::default(),
+            ..RequestFrame::default()
+        };
+        assert!(matches!(
+            RequestBody::builder().frame(frame()).delay(1).build(),
+            Err(ApiError::BadDelay(1))
+        ));
+        assert!(matches!(
+            RequestBody::builder()
+                .frames((0..1001).map(|_| frame()))
+                .build(),
+            Err(ApiError::TooManyFrames(1001))
+        ));
+    }
+}
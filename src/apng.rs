@@ -0,0 +1,163 @@
+use bytes::{BufMut, BytesMut};
+use miniz_oxide::deflate::compress_to_vec_zlib;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+const COLOR_TYPE_RGB: u8 = 2;
+const COLOR_TYPE_INDEXED: u8 = 3;
+
+#[derive(Copy, Clone)]
+pub enum DisposeOp {
+    None = 0,
+}
+
+#[derive(Copy, Clone)]
+pub enum BlendOp {
+    Source = 0,
+    Over = 1,
+}
+
+pub struct FrameControl {
+    pub sequence_number: u32,
+    pub left: usize,
+    pub top: usize,
+    pub width: usize,
+    pub height: usize,
+    pub delay_ms: u32,
+    pub dispose_op: DisposeOp,
+    pub blend_op: BlendOp,
+}
+
+fn encode_chunk(output: &mut BytesMut, kind: &[u8; 4], data: &[u8]) {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+
+    output.put_u32(data.len() as u32);
+    output.put_slice(kind);
+    output.put_slice(data);
+    output.put_u32(crc.finalize());
+}
+
+fn encode_header(output: &mut BytesMut, (width, height): (usize, usize), color_type: u8) {
+    output.put_slice(&SIGNATURE);
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.put_u32(width as u32);
+    ihdr.put_u32(height as u32);
+    ihdr.put_u8(8); // bit depth
+    ihdr.put_u8(color_type);
+    ihdr.put_u8(0); // compression method
+    ihdr.put_u8(0); // filter method
+    ihdr.put_u8(0); // no interlace
+    encode_chunk(output, b"IHDR", &ihdr);
+}
+
+/// Encodes the PNG signature, header, animation control and palette.
+/// The animation is played `num_plays` times, or forever if `0`.
+/// Pixels equal to `transparent` are fully transparent, so that frames
+/// blended with [`BlendOp::Over`] keep the previous canvas.
+pub fn encode_preamble(
+    output: &mut BytesMut,
+    size: (usize, usize),
+    num_frames: u32,
+    num_plays: u16,
+    palette: &[u8],
+    transparent: u8,
+) {
+    encode_header(output, size, COLOR_TYPE_INDEXED);
+
+    let mut actl = Vec::with_capacity(8);
+    actl.put_u32(num_frames);
+    actl.put_u32(u32::from(num_plays)); // 0 to loop forever
+    encode_chunk(output, b"acTL", &actl);
+
+    encode_chunk(output, b"PLTE", palette);
+
+    let mut trns = vec![0xff; usize::from(transparent) + 1];
+    trns[usize::from(transparent)] = 0;
+    encode_chunk(output, b"tRNS", &trns);
+}
+
+/// Encodes the PNG signature and header of a still true color image.
+pub fn encode_rgb_preamble(output: &mut BytesMut, size: (usize, usize)) {
+    encode_header(output, size, COLOR_TYPE_RGB);
+}
+
+/// Encodes the physical pixel size, as `density` times 72 pixels per inch,
+/// so that viewers that honor it show high-DPI images at their intended
+/// size.
+pub fn encode_density(output: &mut BytesMut, density: u8) {
+    let pixels_per_meter = 2835 * u32::from(density);
+    let mut phys = Vec::with_capacity(9);
+    phys.put_u32(pixels_per_meter);
+    phys.put_u32(pixels_per_meter);
+    phys.put_u8(1); // meter
+    encode_chunk(output, b"pHYs", &phys);
+}
+
+pub fn encode_comment(output: &mut BytesMut, comment: &[u8]) {
+    let mut itxt = Vec::with_capacity(13 + comment.len());
+    itxt.extend_from_slice(b"Comment\0");
+    itxt.put_u8(0); // uncompressed
+    itxt.put_u8(0); // compression method
+    itxt.put_u8(0); // empty language tag
+    itxt.put_u8(0); // empty translated keyword
+    itxt.extend_from_slice(comment);
+    encode_chunk(output, b"iTXt", &itxt);
+}
+
+pub fn encode_frame_control(output: &mut BytesMut, ctrl: &FrameControl) {
+    let mut fctl = Vec::with_capacity(26);
+    fctl.put_u32(ctrl.sequence_number);
+    fctl.put_u32(ctrl.width as u32);
+    fctl.put_u32(ctrl.height as u32);
+    fctl.put_u32(ctrl.left as u32);
+    fctl.put_u32(ctrl.top as u32);
+    // Prefer centiseconds, which also allow longer delays.
+    let (cs, rem) = (ctrl.delay_ms / 10, ctrl.delay_ms % 10);
+    match u16::try_from(ctrl.delay_ms) {
+        Ok(ms) if rem > 0 => {
+            fctl.put_u16(ms);
+            fctl.put_u16(1000);
+        }
+        _ => {
+            fctl.put_u16(cs.min(u32::from(u16::MAX)) as u16);
+            fctl.put_u16(100);
+        }
+    }
+    fctl.put_u8(ctrl.dispose_op as u8);
+    fctl.put_u8(ctrl.blend_op as u8);
+    encode_chunk(output, b"fcTL", &fctl);
+}
+
+/// Encodes pixel data row by row, with `stride` bytes per row. The first
+/// frame is the default image and goes into `IDAT`, all later frames into
+/// sequenced `fdAT` chunks.
+pub fn encode_image_data(
+    output: &mut BytesMut,
+    sequence_number: Option<u32>,
+    stride: usize,
+    pixels: &[u8],
+) {
+    let mut raw = Vec::with_capacity(pixels.len() + pixels.len() / stride.max(1));
+    for row in pixels.chunks(stride) {
+        raw.push(0); // filter type none
+        raw.extend_from_slice(row);
+    }
+    let compressed = compress_to_vec_zlib(&raw, 6);
+
+    match sequence_number {
+        None => encode_chunk(output, b"IDAT", &compressed),
+        Some(sequence_number) => {
+            let mut fdat = Vec::with_capacity(4 + compressed.len());
+            fdat.put_u32(sequence_number);
+            fdat.extend_from_slice(&compressed);
+            encode_chunk(output, b"fdAT", &fdat);
+        }
+    }
+}
+
+pub fn encode_trailer(output: &mut BytesMut) {
+    encode_chunk(output, b"IEND", &[]);
+}
@@ -0,0 +1,84 @@
+//! In-memory cache of rendered still images.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+};
+
+use bytes::Bytes;
+
+use crate::{api::RequestParams, render::Format};
+
+/// Least recently used cache of finished images, keyed by a hash of the
+/// request. Safe to share between concurrent renders.
+pub struct ImageCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    entries: HashMap<u64, Entry>,
+    tick: u64,
+}
+
+struct Entry {
+    image: Bytes,
+    used: u64,
+}
+
+impl ImageCache {
+    /// Creates a cache holding up to `capacity` images. A capacity of 0
+    /// disables caching.
+    pub fn new(capacity: usize) -> ImageCache {
+        ImageCache {
+            capacity,
+            inner: Mutex::new(Inner {
+                entries: HashMap::with_capacity(capacity),
+                tick: 0,
+            }),
+        }
+    }
+
+    /// Hashes everything that affects the output. Callers should first
+    /// replace the requested size with the square size of the theme, so
+    /// that sizes rounding to the same theme share an entry.
+    pub fn key(params: &RequestParams, format: Format) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        params.hash(&mut hasher);
+        format.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn get(&self, key: u64) -> Option<Bytes> {
+        let mut inner = self.inner.lock().expect("image cache lock");
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.entries.get_mut(&key).map(|entry| {
+            entry.used = tick;
+            entry.image.clone()
+        })
+    }
+
+    /// Inserts an image, evicting the least recently used one if the cache
+    /// is full.
+    pub fn insert(&self, key: u64, image: Bytes) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().expect("image cache lock");
+        if inner.entries.len() >= self.capacity && !inner.entries.contains_key(&key) {
+            if let Some(oldest) = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(&key, _)| key)
+            {
+                inner.entries.remove(&oldest);
+            }
+        }
+        inner.tick += 1;
+        let used = inner.tick;
+        inner.entries.insert(key, Entry { image, used });
+    }
+}
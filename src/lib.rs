@@ -0,0 +1,8 @@
+pub mod api;
+mod apng;
+pub mod cache;
+pub mod pgn;
+pub mod render;
+pub mod stream;
+pub mod theme;
+mod webp;
@@ -1,63 +1,135 @@
-use std::{convert::Infallible, net::SocketAddr};
+use std::net::SocketAddr;
 
 use axum::{
-    body::StreamBody,
+    body::{boxed, StreamBody},
     extract::Query,
-    http::header::CONTENT_TYPE,
+    http::{header::CONTENT_TYPE, StatusCode},
     response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
 use clap::Parser;
-use futures::stream;
+use futures::{stream, StreamExt};
+use serde::Serialize;
 
-mod api;
-mod render;
-mod theme;
-
-use api::{RequestBody, RequestParams};
-use render::Render;
-use theme::Theme;
+use lila_gif::{
+    api::{BoardTheme, RequestBody, RequestParams},
+    cache::ImageCache,
+    render::{Format, Render},
+    stream::render_stream,
+    theme::Themes,
+};
 
 #[derive(Parser)]
 struct Opt {
     /// Listen on this address.
     #[clap(long = "bind", default_value = "127.0.0.1:6175")]
     bind: SocketAddr,
+    /// Cache up to this many rendered images. Disabled by default.
+    #[clap(long = "image-cache", default_value = "0")]
+    image_cache: usize,
+    /// Average pixels when downsampling sprites for smaller square sizes,
+    /// instead of picking the nearest one.
+    #[clap(long = "smooth-sprites")]
+    smooth_sprites: bool,
+    /// Enlarge (or shrink) the pieces within their squares by this factor.
+    #[clap(long = "piece-scale", default_value = "1", value_parser = parse_piece_scale)]
+    piece_scale: f32,
+}
+
+fn parse_piece_scale(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(scale) if scale > 0.0 && scale.is_finite() => Ok(scale),
+        _ => Err("expected a positive number".to_owned()),
+    }
+}
+
+/// Streams the rendered image, rendering on the blocking thread pool. The
+/// first part is rendered before responding, so that failures can still be
+/// reported with an error status.
+async fn stream_render(format: Format, render: Render) -> Response {
+    let mut parts = Box::pin(render_stream(render));
+    match parts.next().await {
+        Some(Err(err)) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        first => Response::builder()
+            .header(CONTENT_TYPE, format.content_type())
+            .body(boxed(StreamBody::new(stream::iter(first).chain(parts))))
+            .unwrap(),
+    }
+}
+
+async fn image(
+    themes: &'static Themes,
+    cache: Option<&'static ImageCache>,
+    format: Format,
+    Query(req): Query<RequestParams>,
+) -> Response {
+    let render = match cache {
+        Some(cache) => Render::try_from_cached_params(cache, themes, req, format),
+        None => Render::try_from_params(themes, req, format),
+    };
+    match render {
+        Ok(render) => stream_render(format, render).await,
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
+}
+
+async fn game(themes: &'static Themes, format: Format, Json(req): Json<RequestBody>) -> Response {
+    match Render::try_from_request(themes, req, format) {
+        Ok(render) => stream_render(format, render).await,
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
 }
 
-async fn image(theme: &'static Theme, Query(req): Query<RequestParams>) -> impl IntoResponse {
-    Response::builder()
-        .header(CONTENT_TYPE, "image/gif")
-        .body(StreamBody::new(stream::iter(
-            Render::new_image(theme, req).map(Ok::<_, Infallible>),
-        )))
-        .unwrap()
+#[derive(Serialize)]
+struct ThemeList {
+    #[serde(rename = "boardThemes")]
+    board_themes: [&'static str; BoardTheme::ALL.len()],
 }
 
-async fn game(theme: &'static Theme, Json(req): Json<RequestBody>) -> impl IntoResponse {
-    Response::builder()
-        .header(CONTENT_TYPE, "image/gif")
-        .body(StreamBody::new(stream::iter(
-            Render::new_animation(theme, req).map(Ok::<_, Infallible>),
-        )))
-        .unwrap()
+/// Lists the values of the `theme` parameter, for selectors. There is
+/// only the one bundled piece set.
+async fn theme_list() -> Json<ThemeList> {
+    Json(ThemeList {
+        board_themes: BoardTheme::ALL.map(BoardTheme::name),
+    })
 }
 
-async fn example(theme: &'static Theme) -> impl IntoResponse {
-    game(theme, Json(RequestBody::example())).await
+async fn example(themes: &'static Themes) -> Response {
+    game(themes, Format::Gif, Json(RequestBody::example())).await
 }
 
 #[tokio::main]
 async fn main() {
     let opt = Opt::parse();
 
-    let theme: &'static Theme = Box::leak(Box::new(Theme::new()));
+    let themes: &'static Themes = Box::leak(Box::new(Themes::with_options(
+        opt.smooth_sprites,
+        opt.piece_scale,
+    )));
+    let cache: Option<&'static ImageCache> =
+        (opt.image_cache > 0).then(|| &*Box::leak(Box::new(ImageCache::new(opt.image_cache))));
 
     let app = Router::new()
-        .route("/image.gif", get(move |req| image(theme, req)))
-        .route("/game.gif", post(move |req| game(theme, req)))
-        .route("/example.gif", get(move || example(theme)));
+        .route(
+            "/image.gif",
+            get(move |req| image(themes, cache, Format::Gif, req)),
+        )
+        .route(
+            "/image.png",
+            get(move |req| image(themes, cache, Format::Png, req)),
+        )
+        .route("/game.gif", post(move |req| game(themes, Format::Gif, req)))
+        .route(
+            "/game.png",
+            post(move |req| game(themes, Format::Apng, req)),
+        )
+        .route(
+            "/game.webp",
+            post(move |req| game(themes, Format::Webp, req)),
+        )
+        .route("/themes", get(theme_list))
+        .route("/example.gif", get(move || example(themes)));
 
     axum::Server::bind(&opt.bind)
         .serve(app.into_make_service())
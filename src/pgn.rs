@@ -0,0 +1,267 @@
+//! Expands the mainline of a PGN into frames.
+
+use std::{error, fmt};
+
+use shakmaty::{
+    fen::{Fen, ParseFenError},
+    san::{SanError, SanPlus},
+    uci::Uci,
+    CastlingMode, Chess, EnPassantMode, FromSetup, Position, PositionErrorKinds,
+};
+
+use crate::api::{ApiError, CheckSquare, RequestFrame, MAX_FRAMES};
+
+#[derive(Debug)]
+pub enum PgnError {
+    BadFen(ParseFenError),
+    IllegalFen(PositionErrorKinds),
+    BadSan {
+        ply: usize,
+        token: String,
+    },
+    IllegalMove {
+        ply: usize,
+        san: SanPlus,
+        err: SanError,
+    },
+    Unbalanced(char),
+    /// The mainline has more moves than frames are allowed.
+    TooManyFrames,
+    /// The frames do not make a valid request together with the other
+    /// parameters.
+    Invalid(ApiError),
+}
+
+impl fmt::Display for PgnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PgnError::BadFen(err) => write!(f, "bad fen header: {}", err),
+            PgnError::IllegalFen(kinds) => write!(f, "illegal fen header: {:?}", kinds),
+            PgnError::BadSan { ply, token } => write!(f, "bad san at ply {}: {}", ply, token),
+            PgnError::IllegalMove { ply, san, err } => {
+                write!(f, "illegal move at ply {}: {} ({})", ply, san, err)
+            }
+            PgnError::Unbalanced(c) => write!(f, "unbalanced {:?}", c),
+            PgnError::TooManyFrames => write!(f, "too many moves (max {} frames)", MAX_FRAMES),
+            PgnError::Invalid(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for PgnError {}
+
+/// Tokens of the mainline, and the value of the `FEN` header if any.
+/// Comments, variations and move numbers are skipped.
+fn tokenize(pgn: &str) -> Result<(Option<&str>, Vec<&str>), PgnError> {
+    let mut fen = None;
+    let mut tokens = Vec::new();
+    let mut depth = 0usize;
+    let mut rest = pgn;
+
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '[' => {
+                let end = rest.find(']').ok_or(PgnError::Unbalanced('['))?;
+                let header = &rest[1..end];
+                if let Some(value) = header.trim_start().strip_prefix("FEN") {
+                    fen = Some(value.trim().trim_matches('"'));
+                }
+                rest = &rest[end + 1..];
+            }
+            '{' => {
+                let end = rest.find('}').ok_or(PgnError::Unbalanced('{'))?;
+                rest = &rest[end + 1..];
+            }
+            ';' => {
+                rest = rest.find('\n').map_or("", |end| &rest[end + 1..]);
+            }
+            '(' => {
+                depth += 1;
+                rest = &rest[1..];
+            }
+            ')' => {
+                depth = depth.checked_sub(1).ok_or(PgnError::Unbalanced(')'))?;
+                rest = &rest[1..];
+            }
+            c if c.is_whitespace() => {
+                rest = &rest[c.len_utf8()..];
+            }
+            _ => {
+                let end = rest
+                    .find(|c: char| c.is_whitespace() || "[]{}();".contains(c))
+                    .unwrap_or(rest.len());
+                if depth == 0 {
+                    tokens.push(&rest[..end]);
+                }
+                rest = &rest[end..];
+            }
+        }
+    }
+
+    if depth > 0 {
+        return Err(PgnError::Unbalanced('('));
+    }
+    Ok((fen, tokens))
+}
+
+/// Plays the mainline of a PGN and returns a frame for the starting
+/// position followed by a frame for each half-move. Games may start from
+/// a custom position given in a `FEN` header. Parsing stops with an error
+/// once there would be more than `MAX_FRAMES` frames.
+pub fn frames(pgn: &str) -> Result<Vec<RequestFrame>, PgnError> {
+    let (fen, tokens) = tokenize(pgn)?;
+
+    let setup = match fen {
+        Some(fen) => fen.parse::<Fen>().map_err(PgnError::BadFen)?.0,
+        None => Fen::default().0,
+    };
+    let mode = CastlingMode::detect(&setup);
+    let mut pos =
+        Chess::from_setup(setup, mode).map_err(|err| PgnError::IllegalFen(err.kinds()))?;
+
+    let frame = |pos: &Chess, last_move: Option<Uci>| RequestFrame {
+        fen: Fen(pos.clone().into_setup(EnPassantMode::Always)),
+        check: if pos.is_check() {
+            CheckSquare::Yes
+        } else {
+            CheckSquare::No
+        },
+        mate: pos.is_checkmate(),
+        last_move,
+        ..RequestFrame::default()
+    };
+
+    let mut frames = vec![frame(&pos, None)];
+
+    for token in tokens {
+        if matches!(token, "1-0" | "0-1" | "1/2-1/2" | "*") {
+            break;
+        }
+        // Move numbers may be attached to the move, like 12.e4 or 12...e5.
+        let token = token.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.');
+        let token = token.trim_end_matches(['!', '?']);
+        if token.is_empty() || token.starts_with('$') {
+            continue;
+        }
+
+        if frames.len() == MAX_FRAMES {
+            return Err(PgnError::TooManyFrames);
+        }
+
+        let ply = frames.len();
+        let san: SanPlus = token.parse().map_err(|_| PgnError::BadSan {
+            ply,
+            token: token.to_owned(),
+        })?;
+        let m = san
+            .san
+            .to_move(&pos)
+            .map_err(|err| PgnError::IllegalMove { ply, san, err })?;
+        pos.play_unchecked(&m);
+
+        frames.push(frame(&pos, Some(Uci::from_move(&m, mode))));
+    }
+
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::Square;
+
+    use super::*;
+
+    #[test]
+    fn mainline() {
+        let frames = frames(
+            "[Event \"Casual\"]\n\n1. e4 {best by test} e5 (1... c5 2. Nf3) 2. Nf3 Nc6 \
+             3. Bb5 a6 $1 4. Ba4!? 1/2-1/2",
+        )
+        .unwrap();
+        assert_eq!(frames.len(), 8);
+        assert_eq!(frames[0].fen, Fen::default());
+        assert!(frames[0].last_move.is_none());
+        assert_eq!(frames[1].last_move, Some("e2e4".parse().unwrap()));
+        assert_eq!(frames[7].last_move, Some("b5a4".parse().unwrap()));
+        assert_eq!(
+            frames[7].fen.to_string(),
+            "r1bqkbnr/1ppp1ppp/p1n5/4p3/B3P3/5N2/PPPP1PPP/RNBQK2R b KQkq - 1 4"
+        );
+    }
+
+    #[test]
+    fn custom_start_and_mate() {
+        let frames =
+            frames("[SetUp \"1\"]\n[FEN \"6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1\"]\n\n1. Ra8#")
+                .unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!(matches!(frames[1].check, CheckSquare::Yes));
+        assert!(frames[1].mate);
+        assert_eq!(
+            frames[1].check.to_square(&frames[1].fen.0),
+            Some(Square::G8)
+        );
+    }
+
+    #[test]
+    fn empty() {
+        // Nothing but the starting position, like a game without moves.
+        for pgn in ["", "[White \"?\"]\n\n*"] {
+            let frames = frames(pgn).unwrap();
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0].fen, Fen::default());
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(matches!(
+            frames("1. e4 {unterminated"),
+            Err(PgnError::Unbalanced('{'))
+        ));
+        assert!(matches!(
+            frames("1. e4 (1. d4"),
+            Err(PgnError::Unbalanced('('))
+        ));
+        assert!(matches!(frames("1. e4 )"), Err(PgnError::Unbalanced(')'))));
+        assert!(matches!(
+            frames("1. e4 e5 2. @@"),
+            Err(PgnError::BadSan { ply: 3, .. })
+        ));
+        assert!(matches!(
+            frames("[FEN \"not a fen\"] 1. e4"),
+            Err(PgnError::BadFen(_))
+        ));
+        assert!(matches!(
+            frames("[FEN \"8/8/8/8/8/8/8/8 w - - 0 1\"]"),
+            Err(PgnError::IllegalFen(_))
+        ));
+    }
+
+    #[test]
+    fn illegal_move() {
+        assert!(matches!(
+            frames("1. e4 e5 2. Ke3"),
+            Err(PgnError::IllegalMove { ply: 3, .. })
+        ));
+        assert!(matches!(
+            frames("1. e5"),
+            Err(PgnError::IllegalMove { ply: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn too_many_frames() {
+        let shuffle = |moves: usize| {
+            (0..moves)
+                .map(|i| ["Nf3", "Nf6", "Ng1", "Ng8"][i % 4])
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!(frames(&shuffle(MAX_FRAMES - 1)).unwrap().len(), MAX_FRAMES);
+        assert!(matches!(
+            frames(&shuffle(MAX_FRAMES)),
+            Err(PgnError::TooManyFrames)
+        ));
+    }
+}
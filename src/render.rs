@@ -1,51 +1,476 @@
-use std::{iter::FusedIterator, vec};
+use std::{
+    borrow::Cow,
+    error, fmt,
+    io::{self, Write},
+    iter::{self, FusedIterator},
+    mem,
+    num::NonZeroU16,
+    vec,
+};
 
 use bytes::{BufMut, Bytes, BytesMut};
 use gift::{block, Encoder};
-use ndarray::{s, ArrayViewMut2};
-use shakmaty::{uci::Uci, Bitboard, Board};
+use ndarray::{s, Array2, ArrayViewMut2, ShapeError};
+use rusttype::{point, Scale};
+use shakmaty::{
+    attacks, fen::Fen, san::SanPlus, uci::Uci, Bitboard, Board, ByColor, ByRole, CastlingMode,
+    CastlingSide, Chess, Color, File, FromSetup, Move, Piece, Position, PositionError, Rank, Role,
+    Setup, Square,
+};
 
 use crate::{
-    api::{Comment, Orientation, PlayerName, RequestBody, RequestParams},
-    theme::{SpriteKey, Theme},
+    api::{
+        self, ApiError, Brush, Caption, CheckSquare, Clock, Comment, Disposal, Eval, GameMeta,
+        GameResult, Orientation, PlayerName, RequestBody, RequestFrame, RequestParams, Variant,
+        WeightedArrow, MIN_DELAY,
+    },
+    apng,
+    cache::ImageCache,
+    pgn::{self, PgnError},
+    theme::{Palette, SpriteKey, Theme, Themes},
+    webp,
 };
 
+#[derive(Copy, Clone, Hash)]
+pub enum Format {
+    Gif,
+    Apng,
+    Webp,
+    /// True color still image. Only the first frame is rendered.
+    Png,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Gif => "image/gif",
+            Format::Apng | Format::Png => "image/png",
+            Format::Webp => "image/webp",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RenderError {
+    Gif(gift::Error),
+    Webp(image_webp::EncodingError),
+    Shape(ShapeError),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Gif(err) => write!(f, "gif encoding failed: {}", err),
+            RenderError::Webp(err) => write!(f, "webp encoding failed: {}", err),
+            RenderError::Shape(err) => write!(f, "unexpected buffer shape: {}", err),
+        }
+    }
+}
+
+impl error::Error for RenderError {}
+
+impl From<gift::Error> for RenderError {
+    fn from(err: gift::Error) -> RenderError {
+        RenderError::Gif(err)
+    }
+}
+
+impl From<image_webp::EncodingError> for RenderError {
+    fn from(err: image_webp::EncodingError) -> RenderError {
+        RenderError::Webp(err)
+    }
+}
+
+impl From<ShapeError> for RenderError {
+    fn from(err: ShapeError) -> RenderError {
+        RenderError::Shape(err)
+    }
+}
+
 enum RenderState {
+    /// Finished image from the cache, output as a whole.
+    Cached(Bytes),
     Preamble,
-    Frame(RenderFrame),
+    Frame(Box<RenderFrame>),
     Complete,
 }
 
+#[derive(Clone)]
+struct Player {
+    name: Option<PlayerName>,
+    rating: Option<u16>,
+}
+
+#[derive(Clone)]
 struct PlayerBars {
-    white: PlayerName,
-    black: PlayerName,
+    white: Player,
+    black: Player,
+    clocks: bool,
+    material: bool,
+    turn_indicator: bool,
 }
 
 impl PlayerBars {
-    fn from(white: Option<PlayerName>, black: Option<PlayerName>) -> Option<PlayerBars> {
-        if white.is_some() || black.is_some() {
+    fn from(
+        white: Player,
+        black: Player,
+        clocks: bool,
+        material: bool,
+        turn_indicator: bool,
+    ) -> Option<PlayerBars> {
+        if white.is_some() || black.is_some() || clocks || material || turn_indicator {
             Some(PlayerBars {
-                white: white.unwrap_or_default(),
-                black: black.unwrap_or_default(),
+                white,
+                black,
+                clocks,
+                material,
+                turn_indicator,
             })
         } else {
             None
         }
     }
+
+    fn top(&self, orientation: Orientation) -> (&Player, Color) {
+        orientation.fold((&self.black, Color::Black), (&self.white, Color::White))
+    }
+
+    fn bottom(&self, orientation: Orientation) -> (&Player, Color) {
+        orientation.fold((&self.white, Color::White), (&self.black, Color::Black))
+    }
+
+    fn player(&self, color: Color) -> &Player {
+        color.fold_wb(&self.white, &self.black)
+    }
+}
+
+impl Player {
+    fn is_some(&self) -> bool {
+        self.name.is_some() || self.rating.is_some()
+    }
+}
+
+enum BarUpdate {
+    Full,
+    Clock,
+    Material,
+    Turn,
+}
+
+/// Pieces that one side has in excess of the other, and the resulting
+/// lead in points.
+#[derive(PartialEq, Eq, Clone)]
+struct MaterialLead {
+    pieces: Vec<Role>,
+    points: u32,
+}
+
+/// An arrow between the centers of two squares, or a circle if both
+/// squares are the same.
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct Annotation {
+    from: Square,
+    to: Square,
+    brush: Brush,
+    /// Out of `FULL_WEIGHT`, scaling the width and opacity of arrows.
+    weight: u8,
+}
+
+// Annotation geometry in fractions of a square.
+const ARROW_SHAFT_WIDTH: f32 = 0.16;
+const ARROW_HEAD_WIDTH: f32 = 0.6;
+const ARROW_HEAD_LENGTH: f32 = 0.45;
+const CIRCLE_RADIUS: f32 = 0.47;
+const CIRCLE_STROKE: f32 = 0.07;
+
+/// Weight of annotations that are drawn at full width and opacity.
+const FULL_WEIGHT: u8 = 16;
+/// Relative width of an arrow with the lowest nonzero weight.
+const MIN_ARROW_SCALE: f32 = 0.4;
+/// Share of the pixels drawn for an arrow with the lowest nonzero weight,
+/// out of 16.
+const MIN_ARROW_OPACITY: u8 = 4;
+
+/// Share of the pixels tinted on squares of a check ray, out of 16.
+const CHECK_RAY_OPACITY: u8 = 4;
+/// Share of the background pixels tinted on overlay squares, out of 16.
+const OVERLAY_OPACITY: u8 = 6;
+
+// Legal move markers in fractions of a square.
+const TARGET_DOT_RADIUS: f32 = 0.15;
+const TARGET_RING_RADIUS: f32 = 0.5;
+const TARGET_RING_STROKE: f32 = 0.08;
+
+impl From<api::Annotation> for Annotation {
+    fn from(annotation: api::Annotation) -> Annotation {
+        match annotation {
+            api::Annotation::Arrow { from, to, color } => Annotation {
+                from,
+                to,
+                brush: color,
+                weight: FULL_WEIGHT,
+            },
+            api::Annotation::Circle { square, color } => Annotation {
+                from: square,
+                to: square,
+                brush: color,
+                weight: FULL_WEIGHT,
+            },
+        }
+    }
+}
+
+/// Candidate move arrows, weighted relative to the heaviest one. Arrows
+/// with weight 0 are left out.
+fn weighted_arrows(arrows: &[WeightedArrow]) -> impl Iterator<Item = Annotation> + '_ {
+    let max = arrows.iter().map(|arrow| u32::from(arrow.weight)).max();
+    arrows.iter().filter_map(move |arrow| {
+        let weight = u32::from(arrow.weight) * u32::from(FULL_WEIGHT);
+        let weight = weight.div_ceil(max.filter(|&max| max > 0)?);
+        (weight > 0).then_some(Annotation {
+            from: arrow.from,
+            to: arrow.to,
+            brush: Brush::Blue,
+            weight: weight as u8,
+        })
+    })
+}
+
+impl Annotation {
+    /// Squares that are touched by the rendered annotation.
+    fn squares(&self) -> Bitboard {
+        if self.from == self.to {
+            return Bitboard::from(self.from);
+        }
+        let center = |sq: Square| {
+            (
+                u32::from(sq.file()) as f32 + 0.5,
+                u32::from(sq.rank()) as f32 + 0.5,
+            )
+        };
+        let (x0, y0) = center(self.from);
+        let (x1, y1) = center(self.to);
+        // Sample the center line densely enough so that checking with a
+        // small tolerance can not miss any square.
+        let steps = 100;
+        let reach = ARROW_HEAD_WIDTH / 2.0 + 0.05;
+        (0..=steps)
+            .flat_map(|i| {
+                let t = i as f32 / steps as f32;
+                let (x, y) = (x0 + (x1 - x0) * t, y0 + (y1 - y0) * t);
+                Square::ALL.into_iter().filter(move |&sq| {
+                    let (cx, cy) = center(sq);
+                    let dx = ((x - cx).abs() - 0.5).max(0.0);
+                    let dy = ((y - cy).abs() - 0.5).max(0.0);
+                    dx.hypot(dy) <= reach
+                })
+            })
+            .collect()
+    }
+}
+
+/// Delay of the intermediate frames of a transition, in milliseconds.
+const TRANSITION_DELAY: u32 = 40;
+
+/// Number of intermediate frames when the board flips, with
+/// `flipTransition`.
+const FLIP_TRANSITION_FRAMES: u8 = 4;
+
+/// Longest delay of the extra frame that shows a promoted piece enlarged,
+/// with `promotionOverlay`, in milliseconds.
+const PROMOTION_DELAY: u32 = 500;
+
+/// Squares below the result banner, the center ranks in either
+/// orientation.
+const BANNER_SQUARES: Bitboard = Bitboard(0x0000_00ff_ff00_0000);
+
+/// Delay of the black frame at the end of animations, in milliseconds.
+const KORK_DELAY: u32 = 10;
+
+/// Ordered dithering thresholds.
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Intermediate step of a transition, showing more and more pixels of the
+/// new frame instead of the old frame on the changed squares.
+#[derive(Clone)]
+struct Fade {
+    from: Box<RenderFrame>,
+    squares: Bitboard,
+    step: u8,
+    steps: u8,
+}
+
+impl Fade {
+    fn shows_new(&self, x: usize, y: usize) -> bool {
+        u16::from(BAYER[y % 4][x % 4]) * (u16::from(self.steps) + 1) < 16 * u16::from(self.step)
+    }
 }
 
-#[derive(Default)]
+#[derive(Clone)]
 struct RenderFrame {
+    orientation: Orientation,
     board: Board,
+    turn: Color,
     highlighted: Bitboard,
+    /// Highlights of the moves before the last one, newest first, without
+    /// squares of newer moves.
+    trail: Vec<Bitboard>,
+    /// Squares of a queued premove.
+    premoved: Bitboard,
     checked: Bitboard,
-    delay: Option<u16>,
+    /// Squares between a checking slider and the checked king.
+    check_ray: Bitboard,
+    /// Arbitrary squares to tint, like an attack map.
+    overlay: Bitboard,
+    /// Whether the checked king is checkmated.
+    mate: bool,
+    /// Legal destinations of the selected piece.
+    targets: Bitboard,
+    /// Promoted piece to show enlarged over its destination square.
+    promotion: Option<(Square, Piece)>,
+    annotations: Vec<Annotation>,
+    pockets: Option<ByColor<ByRole<u8>>>,
+    clock: Option<Clock>,
+    eval: Option<Eval>,
+    fade: Option<Fade>,
+    /// Number of entries of the move list played up to this frame.
+    moves: usize,
+    caption: Option<Caption>,
+    /// Whether the result banner covers the center of the board.
+    banner: bool,
+    /// Delay in milliseconds.
+    delay: Option<u32>,
+}
+
+impl Default for RenderFrame {
+    fn default() -> RenderFrame {
+        RenderFrame {
+            orientation: Orientation::White,
+            board: Board::default(),
+            turn: Color::White,
+            highlighted: Bitboard::EMPTY,
+            trail: Vec::new(),
+            premoved: Bitboard::EMPTY,
+            checked: Bitboard::EMPTY,
+            check_ray: Bitboard::EMPTY,
+            overlay: Bitboard::EMPTY,
+            mate: false,
+            targets: Bitboard::EMPTY,
+            promotion: None,
+            annotations: Vec::new(),
+            pockets: None,
+            clock: None,
+            eval: None,
+            fade: None,
+            moves: 0,
+            caption: None,
+            banner: false,
+            delay: None,
+        }
+    }
 }
 
 impl RenderFrame {
+    fn clock_state(&self, color: Color) -> Option<(u32, bool)> {
+        self.clock
+            .map(|clock| (color.fold_wb(clock.white, clock.black), self.turn == color))
+    }
+
+    fn pocket(&self, color: Color) -> Option<&ByRole<u8>> {
+        self.pockets.as_ref().map(|pockets| pockets.get(color))
+    }
+
+    fn material_lead(&self, color: Color) -> MaterialLead {
+        let count = |color: Color, role: Role| {
+            (self.board.by_color(color) & self.board.by_role(role)).count()
+        };
+        let mut pieces = Vec::new();
+        let mut points = 0;
+        for (role, value) in [
+            (Role::Queen, 9),
+            (Role::Rook, 5),
+            (Role::Bishop, 3),
+            (Role::Knight, 3),
+            (Role::Pawn, 1),
+        ] {
+            let (ours, theirs) = (count(color, role), count(!color, role));
+            pieces.resize(pieces.len() + ours.saturating_sub(theirs), role);
+            points += (ours as i32 - theirs as i32) * value;
+        }
+        MaterialLead {
+            pieces,
+            points: points.max(0) as u32,
+        }
+    }
+
+    fn annotated(&self) -> Bitboard {
+        self.annotations
+            .iter()
+            .map(Annotation::squares)
+            .fold(Bitboard::EMPTY, |a, b| a | b)
+    }
+
+    /// Squares covered by the enlarged promoted piece.
+    fn promoting(&self) -> Bitboard {
+        self.promotion.map_or(Bitboard::EMPTY, |(sq, _)| {
+            attacks::king_attacks(sq).with(sq)
+        })
+    }
+
+    fn fading(&self) -> Bitboard {
+        self.fade
+            .as_ref()
+            .map_or(Bitboard::EMPTY, |fade| fade.squares)
+    }
+
+    /// Squares with a different trail highlight than in `prev`.
+    fn trail_diff(&self, prev: &RenderFrame) -> Bitboard {
+        let level = |frame: &RenderFrame, age: usize| {
+            frame.trail.get(age).copied().unwrap_or(Bitboard::EMPTY)
+        };
+        (0..prev.trail.len().max(self.trail.len()))
+            .map(|age| level(prev, age) ^ level(self, age))
+            .fold(Bitboard::EMPTY, |changed, squares| changed | squares)
+    }
+
     fn diff(&self, prev: &RenderFrame) -> Bitboard {
-        (prev.checked ^ self.checked)
+        if prev.orientation != self.orientation {
+            return Bitboard::FULL;
+        }
+
+        let annotated = if prev.annotations != self.annotations {
+            prev.annotated() | self.annotated()
+        } else {
+            Bitboard::EMPTY
+        };
+
+        let promoting = if prev.promotion != self.promotion {
+            prev.promoting() | self.promoting()
+        } else {
+            Bitboard::EMPTY
+        };
+
+        annotated
+            | promoting
+            | prev.fading()
+            | self.fading()
+            | (prev.checked ^ self.checked)
+            | (prev.check_ray ^ self.check_ray)
+            | (prev.overlay ^ self.overlay)
+            | (if prev.banner != self.banner {
+                BANNER_SQUARES
+            } else {
+                Bitboard::EMPTY
+            })
+            | (if prev.mate != self.mate {
+                prev.checked | self.checked
+            } else {
+                Bitboard::EMPTY
+            })
             | (prev.highlighted ^ self.highlighted)
+            | (prev.targets ^ self.targets)
+            | self.trail_diff(prev)
+            | (prev.premoved ^ self.premoved)
             | (prev.board.white() ^ self.board.white())
             | (prev.board.pawns() ^ self.board.pawns())
             | (prev.board.knights() ^ self.board.knights())
@@ -54,299 +479,4339 @@ impl RenderFrame {
             | (prev.board.queens() ^ self.board.queens())
             | (prev.board.kings() ^ self.board.kings())
     }
-}
 
-pub struct Render {
-    theme: &'static Theme,
-    state: RenderState,
-    buffer: Vec<u8>,
-    comment: Option<Comment>,
-    bars: Option<PlayerBars>,
-    orientation: Orientation,
-    frames: vec::IntoIter<RenderFrame>,
-    kork: bool,
-}
+    fn is_unchanged(&self, prev: &RenderFrame, turn_indicator: bool) -> bool {
+        self.diff(prev).is_empty()
+            && (!turn_indicator || self.turn == prev.turn)
+            && self.clock_state(Color::White) == prev.clock_state(Color::White)
+            && self.clock_state(Color::Black) == prev.clock_state(Color::Black)
+            && self.eval == prev.eval
+            && self.pockets == prev.pockets
+            && self.moves == prev.moves
+            && self.caption == prev.caption
+    }
 
-impl Render {
-    pub fn new_image(theme: &'static Theme, params: RequestParams) -> Render {
-        let bars = params.white.is_some() || params.black.is_some();
-        Render {
-            theme,
-            buffer: vec![0; theme.height() * theme.width()],
-            state: RenderState::Preamble,
-            comment: params.comment,
-            bars: PlayerBars::from(params.white, params.black),
-            orientation: params.orientation,
-            frames: vec![RenderFrame {
-                highlighted: highlight_uci(params.last_move),
-                checked: params.check.to_square(&params.fen.0).into_iter().collect(),
-                board: params.fen.0.board,
-                delay: None,
-            }]
-            .into_iter(),
-            kork: false,
+    /// Share of the eval bar filled for white, based on the same winning
+    /// chances as the lichess eval gauge.
+    fn eval_ratio(&self) -> f32 {
+        match self.eval {
+            None => 0.5,
+            Some(Eval::Cp(cp)) => {
+                let cp = cp.clamp(-1000, 1000) as f32;
+                1.0 / (1.0 + (-0.00368208 * cp).exp())
+            }
+            Some(Eval::Mate(mate)) if mate > 0 => 1.0,
+            Some(Eval::Mate(mate)) if mate < 0 => 0.0,
+            Some(Eval::Mate(_)) => self.turn.fold_wb(0.0, 1.0),
         }
     }
+}
 
-    pub fn new_animation(theme: &'static Theme, params: RequestBody) -> Render {
-        let bars = params.white.is_some() || params.black.is_some();
-        let default_delay = params.delay;
-        Render {
-            theme,
-            buffer: vec![0; theme.height() * theme.width()],
-            state: RenderState::Preamble,
-            comment: params.comment,
-            bars: PlayerBars::from(params.white, params.black),
-            orientation: params.orientation,
-            frames: params
-                .frames
-                .into_iter()
-                .map(|frame| RenderFrame {
-                    highlighted: highlight_uci(frame.last_move),
-                    checked: frame.check.to_square(&frame.fen.0).into_iter().collect(),
-                    board: frame.fen.0.board,
-                    delay: Some(frame.delay.unwrap_or(default_delay)),
-                })
-                .collect::<Vec<_>>()
-                .into_iter(),
-            kork: true,
+/// Merges frames that would not change the image into their predecessor,
+/// so that repeated positions only extend the previous delay.
+fn coalesce(frames: impl Iterator<Item = RenderFrame>, turn_indicator: bool) -> Vec<RenderFrame> {
+    let mut coalesced: Vec<RenderFrame> = Vec::new();
+    for frame in frames {
+        match coalesced.last_mut() {
+            Some(prev) if frame.is_unchanged(prev, turn_indicator) => {
+                prev.delay = match (prev.delay, frame.delay) {
+                    (Some(a), Some(b)) => Some(a.saturating_add(b)),
+                    (a, b) => a.or(b),
+                };
+            }
+            _ => coalesced.push(frame),
         }
     }
+    coalesced
 }
 
-impl Iterator for Render {
-    type Item = Bytes;
-
-    fn next(&mut self) -> Option<Bytes> {
-        let mut output = BytesMut::new().writer();
-        match self.state {
-            RenderState::Preamble => {
-                let mut blocks = Encoder::new(&mut output).into_block_enc();
-
-                blocks.encode(block::Header::default()).expect("enc header");
-
-                blocks
-                    .encode(
-                        block::LogicalScreenDesc::default()
-                            .with_screen_height(self.theme.height() as u16)
-                            .with_screen_width(self.theme.width() as u16)
-                            .with_color_table_config(self.theme.color_table_config()),
-                    )
-                    .expect("enc logical screen desc");
-
-                blocks
-                    .encode(self.theme.global_color_table().clone())
-                    .expect("enc global color table");
-
-                blocks
-                    .encode(block::Application::with_loop_count(0))
-                    .expect("enc application");
-
-                let comment = self
-                    .comment
-                    .as_ref()
-                    .map_or("https://github.com/lichess-org/lila-gif".as_bytes(), |c| {
-                        c.as_bytes()
+/// Inserts `steps` intermediate frames between consecutive frames, that
+/// dither from the previous to the next frame on the changed squares.
+/// Frames that flip the board get `flip_steps` intermediate frames instead.
+fn crossfade(frames: Vec<RenderFrame>, steps: u8, flip_steps: u8) -> Vec<RenderFrame> {
+    let mut faded: Vec<RenderFrame> =
+        Vec::with_capacity(frames.len() * (usize::from(steps.max(flip_steps)) + 1));
+    for frame in frames {
+        if let Some(prev) = faded.last() {
+            let steps = if prev.orientation != frame.orientation {
+                flip_steps
+            } else {
+                steps
+            };
+            let squares = frame.diff(prev);
+            if steps > 0 && !squares.is_empty() {
+                let from = Box::new(prev.clone());
+                for step in 1..=steps {
+                    faded.push(RenderFrame {
+                        fade: Some(Fade {
+                            from: from.clone(),
+                            squares,
+                            step,
+                            steps,
+                        }),
+                        delay: Some(TRANSITION_DELAY),
+                        ..frame.clone()
                     });
-                if !comment.is_empty() {
-                    let mut comments = block::Comment::default();
-                    comments.add_comment(comment);
-                    blocks.encode(comments).expect("enc comment");
                 }
+            }
+        }
+        faded.push(frame);
+    }
+    faded
+}
 
-                let view = ArrayViewMut2::from_shape(
-                    (self.theme.height(), self.theme.width()),
-                    &mut self.buffer,
-                )
-                .expect("shape");
+/// Options of an animation that apply to every frame.
+struct FrameSettings {
+    orientation: Orientation,
+    variant: Variant,
+    last_move_arrow: bool,
+    show_check_ray: bool,
+    promotion_overlay: bool,
+    /// Delay in milliseconds.
+    default_delay: u32,
+}
 
-                let mut board_view = view;
+impl FrameSettings {
+    /// Frame of the poster position, shown for the shortest valid delay.
+    fn poster_frame(&self, fen: Fen) -> RenderFrame {
+        self.render_frame(
+            RequestFrame {
+                fen,
+                delay: Some(MIN_DELAY),
+                ..RequestFrame::default()
+            },
+            None,
+            0,
+        )
+    }
 
-                let frame = self.frames.next().unwrap_or_default();
+    /// Converts a requested frame, with `moves` entries of the move list
+    /// played up to it. `before` is the board of the previous frame, if
+    /// any.
+    fn render_frame(
+        &self,
+        frame: RequestFrame,
+        before: Option<&Board>,
+        moves: usize,
+    ) -> RenderFrame {
+        RenderFrame {
+            orientation: frame.orientation.unwrap_or(self.orientation),
+            highlighted: highlight_uci(frame.last_move.as_ref(), &frame.fen.0.board, before),
+            trail: Vec::new(),
+            premoved: premove_uci(frame.premove.as_ref()),
+            checked: frame.check.to_bitboard(&frame.fen.0),
+            check_ray: if self.show_check_ray {
+                check_ray(&frame.fen.0.board, frame.check.to_bitboard(&frame.fen.0))
+            } else {
+                Bitboard::EMPTY
+            },
+            overlay: Bitboard::EMPTY,
+            mate: frame.mate,
+            targets: legal_targets(&frame.fen.0, frame.selected),
+            promotion: self
+                .promotion_overlay
+                .then(|| promotion_uci(frame.last_move.as_ref(), frame.fen.0.turn))
+                .flatten(),
+            annotations: self
+                .last_move_arrow
+                .then(|| annotate_uci(frame.last_move.as_ref()))
+                .flatten()
+                .into_iter()
+                .chain(
+                    frame
+                        .annotations
+                        .iter()
+                        .map(|&annotation| annotation.into()),
+                )
+                .collect(),
+            turn: frame.fen.0.turn,
+            board: frame.fen.0.board,
+            pockets: match self.variant {
+                Variant::Standard => None,
+                Variant::Crazyhouse => frame.fen.0.pockets,
+            },
+            clock: frame.clock,
+            eval: frame.eval,
+            fade: None,
+            moves,
+            caption: frame.caption,
+            banner: false,
+            delay: Some(
+                frame
+                    .delay_ms
+                    .or(frame.delay.map(|delay| u32::from(delay) * 10))
+                    .unwrap_or(self.default_delay),
+            ),
+        }
+    }
+}
 
-                if let Some(delay) = frame.delay {
-                    let mut ctrl = block::GraphicControl::default();
-                    ctrl.set_delay_time_cs(delay);
-                    blocks.encode(ctrl).expect("enc graphic control");
-                }
+/// Precedes a frame with a promotion by a short frame that shows the
+/// promoted piece enlarged.
+fn with_promotion_overlay(frame: RenderFrame) -> impl Iterator<Item = RenderFrame> {
+    let overlay = frame.promotion.is_some().then(|| RenderFrame {
+        delay: frame.delay.map(|delay| delay.min(PROMOTION_DELAY)),
+        ..frame.clone()
+    });
+    overlay.into_iter().chain(iter::once(RenderFrame {
+        promotion: None,
+        ..frame
+    }))
+}
 
-                render_diff(
-                    board_view.as_slice_mut().expect("continguous"),
-                    self.theme,
-                    self.orientation,
-                    None,
-                    &frame,
-                );
+/// Converts the frames of a stream one at a time, keeping what later
+/// frames depend on: the previous board and the trail of recent moves.
+struct LiveFrames {
+    settings: FrameSettings,
+    trail: usize,
+    recent: Vec<Bitboard>,
+    before: Option<Board>,
+}
 
-                blocks
-                    .encode(
-                        block::ImageDesc::default()
-                            .with_height(self.theme.height() as u16)
-                            .with_width(self.theme.width() as u16),
-                    )
-                    .expect("enc image desc");
+impl LiveFrames {
+    /// Converts the next requested frame, which becomes two frames with a
+    /// promotion overlay.
+    fn convert(&mut self, frame: RequestFrame) -> impl Iterator<Item = RenderFrame> {
+        let mut frame = self.settings.render_frame(frame, self.before.as_ref(), 0);
+        follow_trail(&mut frame, &mut self.recent, self.trail);
+        self.before = Some(frame.board.clone());
+        with_promotion_overlay(frame)
+    }
+}
 
-                let mut image_data = block::ImageData::new(self.buffer.len());
-                image_data.data_mut().extend_from_slice(&self.buffer);
-                blocks.encode(image_data).expect("enc image data");
+/// Frames still to be rendered.
+enum Frames {
+    Vec(vec::IntoIter<RenderFrame>),
+    /// Pulled from an iterator on demand, with the next frame taken in
+    /// advance, so that it is known before rendering starts.
+    Lazy {
+        next: Option<Box<RenderFrame>>,
+        rest: Box<dyn Iterator<Item = RenderFrame> + Send>,
+    },
+}
 
-                self.state = RenderState::Frame(frame);
-            }
-            RenderState::Frame(ref prev) => {
-                let mut blocks = Encoder::new(&mut output).into_block_enc();
+impl Frames {
+    fn lazy(mut frames: Box<dyn Iterator<Item = RenderFrame> + Send>) -> Frames {
+        Frames::Lazy {
+            next: frames.next().map(Box::new),
+            rest: frames,
+        }
+    }
 
-                if let Some(frame) = self.frames.next() {
-                    let mut ctrl = block::GraphicControl::default();
-                    ctrl.set_disposal_method(block::DisposalMethod::Keep);
-                    ctrl.set_transparent_color_idx(self.theme.transparent_color());
-                    if let Some(delay) = frame.delay {
-                        ctrl.set_delay_time_cs(delay);
-                    }
-                    blocks.encode(ctrl).expect("enc graphic control");
-
-                    let ((left, y), (w, h)) = render_diff(
-                        &mut self.buffer,
-                        self.theme,
-                        self.orientation,
-                        Some(prev),
-                        &frame,
-                    );
-
-                    let top = y + if self.bars.is_some() {
-                        self.theme.bar_height()
-                    } else {
-                        0
-                    };
+    fn first(&self) -> Option<&RenderFrame> {
+        match self {
+            Frames::Vec(frames) => frames.as_slice().first(),
+            Frames::Lazy { next, .. } => next.as_deref(),
+        }
+    }
 
-                    blocks
-                        .encode(
-                            block::ImageDesc::default()
-                                .with_left(left as u16)
-                                .with_top(top as u16)
-                                .with_height(h as u16)
-                                .with_width(w as u16),
-                        )
-                        .expect("enc image desc");
-
-                    let mut image_data = block::ImageData::new(w * h);
-                    image_data
-                        .data_mut()
-                        .extend_from_slice(&self.buffer[..(w * h)]);
-                    blocks.encode(image_data).expect("enc image data");
+    /// Whether no frames remain. Exact even for lazy frames, because the
+    /// next frame is taken in advance.
+    fn is_empty(&self) -> bool {
+        match self {
+            Frames::Vec(frames) => frames.len() == 0,
+            Frames::Lazy { next, .. } => next.is_none(),
+        }
+    }
 
-                    self.state = RenderState::Frame(frame);
-                } else {
-                    // Add a black frame at the end, to work around twitter
-                    // cutting off the last frame.
-                    if self.kork {
-                        let mut ctrl = block::GraphicControl::default();
-                        ctrl.set_disposal_method(block::DisposalMethod::Keep);
-                        ctrl.set_transparent_color_idx(self.theme.transparent_color());
-                        ctrl.set_delay_time_cs(1);
-                        blocks.encode(ctrl).expect("enc graphic control");
-
-                        let height = self.theme.height();
-                        let width = self.theme.width();
-                        blocks
-                            .encode(
-                                block::ImageDesc::default()
-                                    .with_left(0)
-                                    .with_top(0)
-                                    .with_height(height as u16)
-                                    .with_width(width as u16),
-                            )
-                            .expect("enc image desc");
+    /// Number of remaining frames. Only a lower bound for lazy frames.
+    fn len(&self) -> usize {
+        match self {
+            Frames::Vec(frames) => frames.len(),
+            Frames::Lazy { next, rest } => usize::from(next.is_some()) + rest.size_hint().0,
+        }
+    }
+}
 
-                        let mut image_data = block::ImageData::new(height * width);
-                        image_data
-                            .data_mut()
-                            .resize(height * width, self.theme.bar_color());
-                        blocks.encode(image_data).expect("enc image data");
-                    }
+impl Iterator for Frames {
+    type Item = RenderFrame;
 
-                    blocks
-                        .encode(block::Trailer::default())
-                        .expect("enc trailer");
-                    self.state = RenderState::Complete;
-                }
+    fn next(&mut self) -> Option<RenderFrame> {
+        match self {
+            Frames::Vec(frames) => frames.next(),
+            Frames::Lazy { next, rest } => {
+                mem::replace(next, rest.next().map(Box::new)).map(|frame| *frame)
             }
-            RenderState::Complete => return None,
         }
-        Some(output.into_inner().freeze())
     }
 }
 
-impl FusedIterator for Render {}
+pub struct Render {
+    theme: &'static Theme,
+    palette: Palette,
+    state: RenderState,
+    buffer: Vec<u8>,
+    comment: Option<Comment>,
+    /// Whether GIF comments are restricted to ASCII.
+    ascii_comments: bool,
+    metadata: Option<GameMeta>,
+    /// FEN of a still image, embedded as a line of the metadata.
+    embedded_fen: Option<String>,
+    bars: Option<PlayerBars>,
+    coordinates: bool,
+    /// Whether ranks and files are labeled in a margin around the board.
+    coordinate_margin: bool,
+    blindfold: bool,
+    /// Whether pieces of the side at the top of the board are upside down.
+    rotate_opponent_pieces: bool,
+    /// Whether files are reversed on screen, independent of the
+    /// orientation.
+    mirror: bool,
+    /// Whether thin lines are drawn between the squares.
+    grid: bool,
+    /// Pixels per pixel of the requested size, declared in PNGs.
+    density: u8,
+    /// Prerendered watermark for the bottom right corner of the board.
+    watermark: Option<Array2<u8>>,
+    /// Prerendered result banner for the center of the last frame.
+    banner: Option<Array2<u8>>,
+    /// Visible part of the board, in squares on screen.
+    squares: Rect,
+    pockets: bool,
+    eval_bar: bool,
+    /// Moves in SAN with move numbers, if shown next to the board.
+    move_list: Option<Vec<String>>,
+    /// Whether frames have a caption strip below the board.
+    captions: bool,
+    frames: Frames,
+    /// Second board of a still image, shown to the right of the first.
+    second: Option<RenderFrame>,
+    /// Whether areas around the board are filled with the transparent
+    /// color, which then also applies to the first frame.
+    transparent_background: bool,
+    kork: bool,
+    /// Encode distant changes of a frame as separate image blocks.
+    split_regions: bool,
+    /// Interlace the first frame of a GIF, which covers the whole screen.
+    interlace: bool,
+    /// Disposal of GIF frames. Frames only draw changes on top of the
+    /// previous frame, which assumes `Disposal::Keep`, so every frame is
+    /// drawn in full with any other disposal.
+    disposal: Disposal,
+    loops: Option<NonZeroU16>,
+    format: Format,
+    sequence_number: u32,
+    /// Milliseconds that GIF delays are ahead (positive) or behind
+    /// (negative) of the requested delays so far.
+    delay_error: i64,
+    webp: Option<webp::Animation>,
+    cache: Option<CacheWriter>,
+    /// Reused for all parts of the output. Parts are split off, so that
+    /// the allocation can be reclaimed once they are dropped.
+    output: BytesMut,
+}
 
-fn render_diff(
-    buffer: &mut [u8],
-    theme: &Theme,
-    orientation: Orientation,
-    prev: Option<&RenderFrame>,
-    frame: &RenderFrame,
-) -> ((usize, usize), (usize, usize)) {
-    let diff = prev.map_or(Bitboard::FULL, |p| p.diff(frame));
+/// A frame rendered into the buffer, ready to be encoded.
+struct Step {
+    rect: Rect,
+    regions: Vec<Rect>,
+    delay: Option<u32>,
+    first: bool,
+}
 
-    let x_min = diff
-        .into_iter()
-        .map(|sq| orientation.x(sq))
-        .min()
-        .unwrap_or(0);
-    let y_min = diff
-        .into_iter()
-        .map(|sq| orientation.y(sq))
-        .min()
-        .unwrap_or(0);
-    let x_max = diff
-        .into_iter()
-        .map(|sq| orientation.x(sq))
-        .max()
-        .unwrap_or(0)
-        + 1;
-    let y_max = diff
-        .into_iter()
-        .map(|sq| orientation.y(sq))
-        .max()
-        .unwrap_or(0)
-        + 1;
+/// A frame rendered by [`Render::render_next_diff()`], not yet encoded.
+#[cfg(feature = "bench")]
+pub struct RenderedFrame(Step);
 
-    let width = (x_max - x_min) * theme.square();
-    let height = (y_max - y_min) * theme.square();
+/// Collects the output of a render, to be cached once complete.
+struct CacheWriter {
+    cache: &'static ImageCache,
+    key: u64,
+    image: BytesMut,
+}
 
-    let mut view = ArrayViewMut2::from_shape((height, width), buffer).expect("shape");
+impl Render {
+    pub fn new_image(theme: &'static Theme, params: RequestParams, format: Format) -> Render {
+        Render::image(theme, params, format).with_buffer()
+    }
 
-    if prev.is_some() {
-        view.fill(theme.transparent_color());
+    /// Like `new_image()`, but outputs the finished image from the cache if
+    /// an identical request was rendered before, and otherwise adds it to
+    /// the cache once complete.
+    pub fn new_cached_image(
+        cache: &'static ImageCache,
+        theme: &'static Theme,
+        mut params: RequestParams,
+        format: Format,
+    ) -> Render {
+        params.size = Some(theme.square() as u16);
+        let key = ImageCache::key(&params, format);
+        let mut render = Render::image(theme, params, format);
+        match cache.get(key) {
+            Some(image) => render.state = RenderState::Cached(image),
+            None => {
+                render = render.with_buffer();
+                render.cache = Some(CacheWriter {
+                    cache,
+                    key,
+                    image: BytesMut::new(),
+                });
+            }
+        }
+        render
     }
 
-    for sq in diff {
-        let key = SpriteKey {
-            piece: frame.board.piece_at(sq),
-            dark_square: sq.is_dark(),
-            highlight: frame.highlighted.contains(sq),
-            check: frame.checked.contains(sq),
+    fn image(theme: &'static Theme, params: RequestParams, format: Format) -> Render {
+        let bars = PlayerBars::from(
+            Player {
+                name: params.white,
+                rating: params.white_rating,
+            },
+            Player {
+                name: params.black,
+                rating: params.black_rating,
+            },
+            false,
+            params.material,
+            params.turn_indicator,
+        );
+        let orient = |fen: &Fen| {
+            if params.auto_orient {
+                Orientation::from_color(fen.0.turn)
+            } else {
+                params.orientation
+            }
+        };
+        let orientation = orient(&params.fen);
+        let board_frame = |fen: &Fen,
+                           last_move: Option<&Uci>,
+                           check: CheckSquare,
+                           orientation: Orientation,
+                           mate: bool,
+                           selected: Option<Square>| RenderFrame {
+            orientation,
+            highlighted: highlight_uci(last_move, &fen.0.board, None),
+            trail: Vec::new(),
+            premoved: Bitboard::EMPTY,
+            checked: check.to_bitboard(&fen.0),
+            check_ray: if params.show_check_ray {
+                check_ray(&fen.0.board, check.to_bitboard(&fen.0))
+            } else {
+                Bitboard::EMPTY
+            },
+            overlay: Bitboard::EMPTY,
+            mate,
+            targets: legal_targets(&fen.0, selected),
+            promotion: None,
+            annotations: params
+                .last_move_arrow
+                .then(|| annotate_uci(last_move))
+                .flatten()
+                .into_iter()
+                .collect(),
+            turn: fen.0.turn,
+            board: fen.0.board.clone(),
+            pockets: match params.variant {
+                Variant::Standard => None,
+                Variant::Crazyhouse => fen.0.pockets.clone(),
+            },
+            clock: None,
+            eval: None,
+            fade: None,
+            moves: 0,
+            caption: None,
+            banner: false,
+            delay: None,
+        };
+        let mut frame = board_frame(
+            &params.fen,
+            params.last_move.as_ref(),
+            params.check,
+            orientation,
+            params.mate,
+            params.selected,
+        );
+        frame.annotations.extend(weighted_arrows(&params.arrows));
+        frame.overlay = params.overlay.unwrap_or(Bitboard::EMPTY);
+        let second = params.second_fen.as_ref().map(|fen| {
+            board_frame(
+                fen,
+                params.second_last_move.as_ref(),
+                params.second_check,
+                params.second_orientation.unwrap_or_else(|| orient(fen)),
+                false,
+                None,
+            )
+        });
+        let squares = params.crop.map_or(Rect::BOARD, |crop| {
+            let x = |sq| screen_x(orientation, params.mirror, sq);
+            Rect::square(x(crop.from), orientation.y(crop.from))
+                .union(Rect::square(x(crop.to), orientation.y(crop.to)))
+        });
+        let palette = theme
+            .palette(
+                params.highlight,
+                params.last_move_arrow
+                    || params.show_check_ray
+                    || params.selected.is_some()
+                    || !params.arrows.is_empty()
+                    || params.overlay.is_some(),
+                params.background,
+                0,
+                false,
+                params.shadows,
+            )
+            .grayscale(params.grayscale)
+            .with_bar_style(theme, params.bar_style);
+        let watermark = params
+            .watermark
+            .filter(|text| !text.is_empty())
+            .map(|text| {
+                render_watermark(
+                    theme,
+                    palette.background(),
+                    palette.text(),
+                    &text,
+                    squares.width * theme.square(),
+                )
+            });
+        // Only after the watermark, which covers the board.
+        let transparent_background = params.transparent_background && matches!(format, Format::Gif);
+        let palette = if transparent_background {
+            palette.with_background(theme.transparent_color())
+        } else {
+            palette
         };
+        Render {
+            theme,
+            palette,
+            buffer: Vec::new(),
+            state: RenderState::Preamble,
+            comment: params.comment,
+            ascii_comments: params.ascii_comments,
+            metadata: None,
+            embedded_fen: params.embed_fen.then(|| params.fen.to_string()),
+            bars,
+            coordinates: params.coordinates,
+            coordinate_margin: params.coordinate_margin,
+            blindfold: params.blindfold,
+            rotate_opponent_pieces: params.rotate_opponent_pieces,
+            mirror: params.mirror,
+            grid: params.grid,
+            density: params.density.unwrap_or(1),
+            watermark,
+            banner: None,
+            squares,
+            pockets: params.variant == Variant::Crazyhouse,
+            eval_bar: false,
+            move_list: None,
+            captions: false,
+            frames: Frames::Vec(vec![frame].into_iter()),
+            second,
+            transparent_background,
+            kork: false,
+            split_regions: false,
+            interlace: params.interlace,
+            disposal: Disposal::Keep,
+            loops: None,
+            format,
+            sequence_number: 0,
+            delay_error: 0,
+            webp: None,
+            cache: None,
+            output: BytesMut::new(),
+        }
+    }
 
-        let left = (orientation.x(sq) - x_min) * theme.square();
-        let top = (orientation.y(sq) - y_min) * theme.square();
+    /// Validates a still image request and renders it with the theme for
+    /// the requested size and density. This is the boundary for untrusted
+    /// input: malformed requests are rejected with an error rather than
+    /// reaching code that assumes valid input.
+    pub fn try_from_params(
+        themes: &'static Themes,
+        params: RequestParams,
+        format: Format,
+    ) -> Result<Render, ApiError> {
+        params.validate()?;
+        Ok(Render::new_image(
+            themes.get_dense(params.size, params.theme, params.density.unwrap_or(1)),
+            params,
+            format,
+        ))
+    }
 
-        view.slice_mut(s!(
-            top..(top + theme.square()),
-            left..(left + theme.square())
+    /// Like [`Render::try_from_params()`], but goes through the image
+    /// cache like `new_cached_image()`. Only valid requests are looked up
+    /// or added.
+    pub fn try_from_cached_params(
+        cache: &'static ImageCache,
+        themes: &'static Themes,
+        params: RequestParams,
+        format: Format,
+    ) -> Result<Render, ApiError> {
+        params.validate()?;
+        Ok(Render::new_cached_image(
+            cache,
+            themes.get_dense(params.size, params.theme, params.density.unwrap_or(1)),
+            params,
+            format,
         ))
-        .assign(&theme.sprite(key));
     }
 
-    (
-        (theme.square() * x_min, theme.square() * y_min),
-        (width, height),
-    )
-}
+    /// Like [`Render::try_from_params()`], but for animations. Excessive
+    /// counts are rejected rather than clamped, like by
+    /// `RequestBody::validate()`. Moves need no sanitizing, since their
+    /// squares are always on the board once parsed, and moves that do not
+    /// fit the position are only highlighted.
+    pub fn try_from_request(
+        themes: &'static Themes,
+        params: RequestBody,
+        format: Format,
+    ) -> Result<Render, ApiError> {
+        params.validate()?;
+        Ok(Render::new_animation(
+            themes.get_dense(params.size, params.theme, params.density.unwrap_or(1)),
+            params,
+            format,
+        ))
+    }
 
-fn highlight_uci(uci: Option<Uci>) -> Bitboard {
-    match uci {
-        Some(Uci::Normal { from, to, .. }) => Bitboard::from(from) | Bitboard::from(to),
-        Some(Uci::Put { to, .. }) => Bitboard::from(to),
-        _ => Bitboard::EMPTY,
+    /// Renders the mainline of a PGN, replacing the frames of `params`.
+    /// The request is then validated like by
+    /// [`Render::try_from_request()`], so that a PGN can not get around the
+    /// limits of the JSON body.
+    pub fn from_pgn(
+        themes: &'static Themes,
+        pgn: &str,
+        mut params: RequestBody,
+        format: Format,
+    ) -> Result<Render, PgnError> {
+        params.frames = pgn::frames(pgn)?;
+        Render::try_from_request(themes, params, format).map_err(PgnError::Invalid)
+    }
+
+    /// Renders the frames of `params`. Requests without frames are rejected
+    /// by `RequestBody::validate()`. If rendered anyway, the output is a
+    /// single frame of the starting position.
+    pub fn new_animation(theme: &'static Theme, params: RequestBody, format: Format) -> Render {
+        let bars = PlayerBars::from(
+            Player {
+                name: params.white,
+                rating: params.white_rating,
+            },
+            Player {
+                name: params.black,
+                rating: params.black_rating,
+            },
+            params.frames.iter().any(|frame| frame.clock.is_some()),
+            params.material,
+            params.turn_indicator,
+        );
+        // Resolved once, so that the board only flips during the game if
+        // frames explicitly ask for another orientation.
+        let orientation = match params.frames.first() {
+            Some(frame) if params.auto_orient => Orientation::from_color(frame.fen.0.turn),
+            _ => params.orientation,
+        };
+        let eval_bar = params.frames.iter().any(|frame| frame.eval.is_some());
+        let captions = params.frames.iter().any(|frame| frame.caption.is_some());
+        let default_delay = params.default_delay_ms();
+        let variant = params.variant;
+        let last_move_arrow = params.last_move_arrow;
+        let brushes = last_move_arrow
+            || params.show_check_ray
+            || params
+                .frames
+                .iter()
+                .any(|frame| !frame.annotations.is_empty() || frame.selected.is_some());
+        let premove = params.frames.iter().any(|frame| frame.premove.is_some());
+        let mut move_list = Vec::new();
+        let mut moves = Vec::with_capacity(params.frames.len());
+        if params.move_list {
+            for (i, frame) in params.frames.iter().enumerate() {
+                if let (Some(prev), Some(uci)) = (i.checked_sub(1), frame.last_move.as_ref()) {
+                    move_list.push(move_list_entry(&params.frames[prev].fen, uci));
+                }
+                moves.push(move_list.len());
+            }
+        }
+        let turn_indicator = params.turn_indicator;
+        let promotion_overlay = params.promotion_overlay;
+        let settings = FrameSettings {
+            orientation,
+            variant,
+            last_move_arrow,
+            show_check_ray: params.show_check_ray,
+            promotion_overlay,
+            default_delay,
+        };
+        let mut before = None;
+        let mut frames = coalesce(
+            params.frames.into_iter().enumerate().map(|(i, frame)| {
+                let frame = settings.render_frame(
+                    frame,
+                    before.as_ref(),
+                    moves.get(i).copied().unwrap_or(0),
+                );
+                before = Some(frame.board.clone());
+                frame
+            }),
+            turn_indicator,
+        );
+        if params.bounce && frames.len() > 2 {
+            // Play back to the second frame, so that looping to the first
+            // frame continues the movement without repeating an endpoint.
+            let backward: Vec<_> = frames[1..frames.len() - 1]
+                .iter()
+                .rev()
+                .map(|frame| RenderFrame {
+                    promotion: None,
+                    ..frame.clone()
+                })
+                .collect();
+            frames.extend(backward);
+        }
+        let trail = trail_len(params.previous_move, params.move_trail);
+        if trail > 0 {
+            let mut recent = Vec::new();
+            for frame in &mut frames {
+                follow_trail(frame, &mut recent, trail);
+            }
+        }
+        if promotion_overlay {
+            frames = frames
+                .into_iter()
+                .flat_map(with_promotion_overlay)
+                .collect();
+        }
+        if frames.is_empty() {
+            frames.push(RenderFrame {
+                orientation,
+                ..RenderFrame::default()
+            });
+        }
+        if params.transition_frames > 0 || params.flip_transition {
+            let flip_steps = if params.flip_transition {
+                FLIP_TRANSITION_FRAMES
+            } else {
+                0
+            };
+            frames = crossfade(frames, params.transition_frames, flip_steps);
+        }
+        if let (Some(delay), Some(last)) = (params.final_delay, frames.last_mut()) {
+            last.delay = Some(u32::from(delay) * 10);
+        }
+        // After transitions, so that the animation cuts from the poster to
+        // the first frame.
+        if let Some(fen) = params.poster {
+            frames.insert(0, settings.poster_frame(fen));
+        }
+        let palette = theme
+            .palette(
+                params.highlight,
+                brushes,
+                None,
+                trail,
+                premove,
+                params.shadows,
+            )
+            .grayscale(params.grayscale)
+            .with_bar_style(theme, params.bar_style);
+        let watermark = params
+            .watermark
+            .filter(|text| !text.is_empty())
+            .map(|text| {
+                render_watermark(
+                    theme,
+                    palette.background(),
+                    palette.text(),
+                    &text,
+                    Rect::BOARD.width * theme.square(),
+                )
+            });
+        let banner = params.result.map(|result| {
+            render_banner(
+                theme,
+                palette.background(),
+                palette.text(),
+                result,
+                Rect::BOARD.width * theme.square(),
+            )
+        });
+        Render {
+            theme,
+            palette,
+            buffer: Vec::new(),
+            state: RenderState::Preamble,
+            comment: params.comment,
+            ascii_comments: params.ascii_comments,
+            metadata: params.metadata,
+            embedded_fen: None,
+            bars,
+            coordinates: params.coordinates,
+            coordinate_margin: params.coordinate_margin,
+            blindfold: params.blindfold,
+            rotate_opponent_pieces: params.rotate_opponent_pieces,
+            mirror: params.mirror,
+            grid: params.grid,
+            density: params.density.unwrap_or(1),
+            watermark,
+            banner,
+            squares: Rect::BOARD,
+            pockets: variant == Variant::Crazyhouse,
+            eval_bar,
+            move_list: params.move_list.then_some(move_list),
+            captions,
+            frames: Frames::Vec(frames.into_iter()),
+            second: None,
+            transparent_background: false,
+            // By default, no black frame at the end when bouncing, since it
+            // would interrupt the oscillation.
+            kork: params.end_frame.unwrap_or(!params.bounce),
+            split_regions: params.split_regions && matches!(format, Format::Gif),
+            interlace: params.interlace,
+            disposal: match format {
+                Format::Gif => params.disposal,
+                _ => Disposal::Keep,
+            },
+            loops: params.loops,
+            format,
+            sequence_number: 0,
+            delay_error: 0,
+            webp: None,
+            cache: None,
+            output: BytesMut::new(),
+        }
+        .with_buffer()
+    }
+
+    /// Like `new_animation()`, but pulls frames from an iterator while the
+    /// output is streamed, instead of holding every position in memory.
+    /// The frames of `params` are replaced. Clocks and the eval bar are
+    /// shown if the first frame has them, and frames that would not change
+    /// the image are kept. `bounce`, `moveList`, `transitionFrames`,
+    /// `flipTransition` and `finalDelay` need all frames in advance and
+    /// are ignored. APNG needs the number of frames in the preamble, so
+    /// frames are collected for it.
+    /// [`LiveRender`] appends frames to a GIF stream started this way.
+    pub fn from_frame_iter<I>(
+        theme: &'static Theme,
+        mut params: RequestBody,
+        frames: I,
+        format: Format,
+    ) -> Render
+    where
+        I: IntoIterator<Item = RequestFrame>,
+        I::IntoIter: Send + 'static,
+    {
+        let mut frames = frames.into_iter().peekable();
+        if matches!(format, Format::Apng) {
+            params.frames = frames.collect();
+            return Render::new_animation(theme, params, format);
+        }
+
+        let (mut render, mut live) = Render::live(theme, params, frames.peek(), format);
+        let poster = mem::replace(&mut render.frames, Frames::Vec(Vec::new().into_iter()));
+        render.frames = Frames::lazy(Box::new(
+            poster.chain(frames.flat_map(move |frame| live.convert(frame))),
+        ));
+        render
+    }
+
+    /// Sets up a render for `from_frame_iter()`, with `first` being the
+    /// first requested frame, if any. Only the poster is added to the
+    /// frames. Later frames are converted by the returned `LiveFrames`.
+    fn live(
+        theme: &'static Theme,
+        params: RequestBody,
+        first: Option<&RequestFrame>,
+        format: Format,
+    ) -> (Render, LiveFrames) {
+        let bars = PlayerBars::from(
+            Player {
+                name: params.white,
+                rating: params.white_rating,
+            },
+            Player {
+                name: params.black,
+                rating: params.black_rating,
+            },
+            first.is_some_and(|frame| frame.clock.is_some()),
+            params.material,
+            params.turn_indicator,
+        );
+        let orientation = match first {
+            Some(frame) if params.auto_orient => Orientation::from_color(frame.fen.0.turn),
+            _ => params.orientation,
+        };
+        let eval_bar = first.is_some_and(|frame| frame.eval.is_some());
+        let captions = first.is_some_and(|frame| frame.caption.is_some());
+        let settings = FrameSettings {
+            orientation,
+            variant: params.variant,
+            last_move_arrow: params.last_move_arrow,
+            show_check_ray: params.show_check_ray,
+            promotion_overlay: params.promotion_overlay,
+            default_delay: params.default_delay_ms(),
+        };
+        let trail = trail_len(params.previous_move, params.move_trail);
+        let poster = params.poster.map(|fen| settings.poster_frame(fen));
+        // Later frames may have annotations and premoves, so always make
+        // room for their colors.
+        let palette = theme
+            .palette(params.highlight, true, None, trail, true, params.shadows)
+            .grayscale(params.grayscale)
+            .with_bar_style(theme, params.bar_style);
+        let watermark = params
+            .watermark
+            .filter(|text| !text.is_empty())
+            .map(|text| {
+                render_watermark(
+                    theme,
+                    palette.background(),
+                    palette.text(),
+                    &text,
+                    Rect::BOARD.width * theme.square(),
+                )
+            });
+        let banner = params.result.map(|result| {
+            render_banner(
+                theme,
+                palette.background(),
+                palette.text(),
+                result,
+                Rect::BOARD.width * theme.square(),
+            )
+        });
+        let render = Render {
+            theme,
+            palette,
+            buffer: Vec::new(),
+            state: RenderState::Preamble,
+            comment: params.comment,
+            ascii_comments: params.ascii_comments,
+            metadata: params.metadata,
+            embedded_fen: None,
+            bars,
+            coordinates: params.coordinates,
+            coordinate_margin: params.coordinate_margin,
+            blindfold: params.blindfold,
+            rotate_opponent_pieces: params.rotate_opponent_pieces,
+            mirror: params.mirror,
+            grid: params.grid,
+            density: params.density.unwrap_or(1),
+            watermark,
+            banner,
+            squares: Rect::BOARD,
+            pockets: params.variant == Variant::Crazyhouse,
+            eval_bar,
+            move_list: None,
+            captions,
+            frames: Frames::Vec(Vec::from_iter(poster).into_iter()),
+            second: None,
+            transparent_background: false,
+            kork: params.end_frame.unwrap_or(true),
+            split_regions: params.split_regions && matches!(format, Format::Gif),
+            interlace: params.interlace,
+            disposal: match format {
+                Format::Gif => params.disposal,
+                _ => Disposal::Keep,
+            },
+            loops: params.loops,
+            format,
+            sequence_number: 0,
+            delay_error: 0,
+            webp: None,
+            cache: None,
+            output: BytesMut::new(),
+        }
+        .with_buffer();
+        let live = LiveFrames {
+            settings,
+            trail,
+            recent: Vec::new(),
+            before: None,
+        };
+        (render, live)
+    }
+}
+
+/// Appends frames to a live GIF stream, one requested frame at a time.
+/// The stream must have been started by `Render::from_frame_iter()` with
+/// the same `params`, so that the color table and layout match. State
+/// that spans frames, like the trail of recent moves and the rounding
+/// error of millisecond delays, is carried from one frame to the next.
+pub struct LiveRender {
+    render: Render,
+    frames: LiveFrames,
+}
+
+impl LiveRender {
+    /// Continues a stream that already shows the frames `shown`, which are
+    /// replayed without rendering them.
+    pub fn new<I>(theme: &'static Theme, params: RequestBody, shown: I) -> LiveRender
+    where
+        I: IntoIterator<Item = RequestFrame>,
+    {
+        let mut shown = shown.into_iter().peekable();
+        let (mut render, mut frames) = Render::live(theme, params, shown.peek(), Format::Gif);
+        let poster = mem::replace(&mut render.frames, Frames::Vec(Vec::new().into_iter()));
+        let mut last = None;
+        for frame in poster.chain(shown.flat_map(|frame| frames.convert(frame))) {
+            if let Some(delay) = frame.delay {
+                render.delay_cs(delay);
+            }
+            last = Some(frame);
+        }
+        // Like `step()`, which renders the default frame for an empty stream,
+        // and the banner on the last frame.
+        let mut last = last.unwrap_or_default();
+        last.banner = render.banner.is_some();
+        render.state = RenderState::Frame(Box::new(last));
+        LiveRender { render, frames }
+    }
+
+    /// Renders only the GIF frames that change the image from the frame
+    /// shown last to `next`, without preamble and trailer. With a
+    /// promotion overlay, that is both frames derived from `next`. No black
+    /// end frame is added.
+    pub fn render_incremental(&mut self, next: RequestFrame) -> Result<Bytes, RenderError> {
+        let frames: Vec<_> = self.frames.convert(next).collect();
+        self.render.frames = Frames::Vec(frames.into_iter());
+        let mut output = BytesMut::new();
+        // `step()` completes the render once frames run out, so stop
+        // before that, to keep the last frame for the next call.
+        while !self.render.frames.is_empty() {
+            match self.render.step()? {
+                Some(step) => self.render.encode_step(&mut output, &step)?,
+                None => break,
+            }
+        }
+        Ok(output.freeze())
+    }
+}
+
+impl Render {
+    fn with_buffer(mut self) -> Render {
+        self.buffer = vec![0; self.width() * self.height()];
+        self
+    }
+
+    fn board_width(&self) -> usize {
+        self.squares.width * self.theme.square()
+    }
+
+    fn board_height(&self) -> usize {
+        self.squares.height * self.theme.square()
+    }
+
+    fn bar_height(&self) -> usize {
+        if self.bars.is_some() {
+            self.theme.bar_height()
+        } else {
+            0
+        }
+    }
+
+    fn pocket_height(&self) -> usize {
+        if self.pockets {
+            self.theme.pocket_height()
+        } else {
+            0
+        }
+    }
+
+    fn caption_height(&self) -> usize {
+        if self.captions {
+            self.theme.bar_height()
+        } else {
+            0
+        }
+    }
+
+    /// Width of the margin left of the board, and height of the margin
+    /// below it.
+    fn margin(&self) -> usize {
+        if self.coordinate_margin {
+            self.theme.coordinate_margin()
+        } else {
+            0
+        }
+    }
+
+    /// Width of the board including its margin, spanned by the bars and
+    /// pockets.
+    fn framed_width(&self) -> usize {
+        self.margin() + self.board_width()
+    }
+
+    fn board_top(&self) -> usize {
+        self.bar_height() + self.pocket_height()
+    }
+
+    fn eval_bar_width(&self) -> usize {
+        if self.eval_bar {
+            self.theme.eval_bar_width()
+        } else {
+            0
+        }
+    }
+
+    fn move_list_width(&self) -> usize {
+        if self.move_list.is_some() {
+            self.theme.move_list_width()
+        } else {
+            0
+        }
+    }
+
+    /// Width of everything belonging to one board.
+    fn panel_width(&self) -> usize {
+        self.framed_width() + self.eval_bar_width() + self.move_list_width()
+    }
+
+    /// Space between the boards of a two-up image.
+    fn two_up_gap(&self) -> usize {
+        self.theme.square() / 3
+    }
+
+    fn width(&self) -> usize {
+        match self.second {
+            Some(_) => 2 * self.panel_width() + self.two_up_gap(),
+            None => self.panel_width(),
+        }
+    }
+
+    fn height(&self) -> usize {
+        self.board_height() + self.margin() + 2 * self.board_top() + self.caption_height()
+    }
+
+    fn encode_preamble(
+        &mut self,
+        output: &mut BytesMut,
+        num_frames: usize,
+    ) -> Result<(), RenderError> {
+        let height = self.height();
+        let width = self.width();
+        // Only an absent comment falls back to the link. An explicitly empty
+        // one omits the block, for minimal output.
+        let comment = self
+            .comment
+            .as_ref()
+            .map_or("https://github.com/lichess-org/lila-gif", |c| c.as_str());
+        let mut metadata = self
+            .metadata
+            .as_ref()
+            .map_or_else(String::new, GameMeta::to_string);
+        if let Some(ref fen) = self.embedded_fen {
+            metadata.push_str(&format!("fen={}\n", fen));
+        }
+
+        match self.format {
+            Format::Gif => {
+                let mut blocks = Encoder::new(output.writer()).into_block_enc();
+
+                blocks.encode(block::Header::default())?;
+
+                let screen_desc = block::LogicalScreenDesc::default()
+                    .with_screen_height(height as u16)
+                    .with_screen_width(width as u16);
+                if self.palette.local_color_table().is_some() {
+                    blocks.encode(screen_desc)?;
+                } else {
+                    blocks.encode(
+                        screen_desc.with_color_table_config(self.palette.color_table_config()),
+                    )?;
+                    blocks.encode(self.palette.global_color_table().clone())?;
+                }
+
+                // The loop count of the NETSCAPE extension is the number of
+                // repetitions, so playing once means omitting it.
+                match self.loops {
+                    None => blocks.encode(block::Application::with_loop_count(0))?,
+                    Some(loops) if loops.get() > 1 => {
+                        blocks.encode(block::Application::with_loop_count(loops.get() - 1))?
+                    }
+                    Some(_) => (),
+                }
+
+                if !comment.is_empty() {
+                    blocks.encode(gif_comment(&ascii_text(comment, self.ascii_comments)))?;
+                }
+                if !metadata.is_empty() {
+                    blocks.encode(gif_comment(&ascii_text(&metadata, self.ascii_comments)))?;
+                }
+            }
+            Format::Apng => {
+                apng::encode_preamble(
+                    output,
+                    (width, height),
+                    num_frames as u32,
+                    self.loops.map_or(0, NonZeroU16::get),
+                    self.palette.global_color_table().colors(),
+                    self.theme.transparent_color(),
+                );
+                if self.density > 1 {
+                    apng::encode_density(output, self.density);
+                }
+                if !comment.is_empty() {
+                    apng::encode_comment(output, comment.as_bytes());
+                }
+                if !metadata.is_empty() {
+                    apng::encode_comment(output, metadata.as_bytes());
+                }
+            }
+            Format::Webp => {
+                self.webp = Some(webp::Animation::new(
+                    (width, height),
+                    self.loops.map_or(0, NonZeroU16::get),
+                ));
+            }
+            Format::Png => {
+                apng::encode_rgb_preamble(output, (width, height));
+                if self.density > 1 {
+                    apng::encode_density(output, self.density);
+                }
+                if !comment.is_empty() {
+                    apng::encode_comment(output, comment.as_bytes());
+                }
+                if !metadata.is_empty() {
+                    apng::encode_comment(output, metadata.as_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rounds a delay to centiseconds for GIF, carrying the rounding error
+    /// over to later frames, so that the total duration stays accurate.
+    fn delay_cs(&mut self, delay: u32) -> u16 {
+        let wanted = i64::from(delay) - self.delay_error;
+        let cs = (wanted + 5).div_euclid(10).clamp(0, i64::from(u16::MAX));
+        self.delay_error += cs * 10 - i64::from(delay);
+        cs as u16
+    }
+
+    /// Encodes the image in `buffer` at the given position on screen.
+    /// Unless `first`, the frame is drawn on top of the previous frames,
+    /// with `theme.transparent_color()` keeping previous pixels.
+    fn encode_frame(
+        &mut self,
+        output: &mut BytesMut,
+        rect: Rect,
+        delay: Option<u32>,
+        first: bool,
+    ) -> Result<(), RenderError> {
+        let Rect {
+            left,
+            top,
+            width: w,
+            height: h,
+        } = rect;
+        match self.format {
+            Format::Gif => {
+                let mut blocks = Encoder::new(output.writer()).into_block_enc();
+
+                // A graphic control extension only applies to the image
+                // right after it, so it can not be shared between frames with
+                // the same delay. Without it, a diff frame would lose the
+                // transparent color that keeps previous pixels, and default
+                // to a delay of 0.
+                if !first {
+                    let mut ctrl = block::GraphicControl::default();
+                    set_disposal(&mut ctrl, self.disposal);
+                    ctrl.set_transparent_color(Some(self.theme.transparent_color()));
+                    if let Some(delay) = delay {
+                        ctrl.set_delay_time_cs(self.delay_cs(delay));
+                    }
+                    blocks.encode(ctrl)?;
+                } else if delay.is_some()
+                    || self.transparent_background
+                    || self.disposal != Disposal::Keep
+                {
+                    let mut ctrl = block::GraphicControl::default();
+                    if self.disposal != Disposal::Keep {
+                        set_disposal(&mut ctrl, self.disposal);
+                    }
+                    if self.transparent_background {
+                        ctrl.set_transparent_color(Some(self.theme.transparent_color()));
+                    }
+                    if let Some(delay) = delay {
+                        ctrl.set_delay_time_cs(self.delay_cs(delay));
+                    }
+                    blocks.encode(ctrl)?;
+                }
+
+                let interlace = first && self.interlace;
+                let image_desc = block::ImageDesc::default()
+                    .with_left(left as u16)
+                    .with_top(top as u16)
+                    .with_height(h as u16)
+                    .with_width(w as u16)
+                    .with_interlaced(interlace);
+                match self.palette.local_color_table() {
+                    Some(table) => {
+                        blocks.encode(
+                            image_desc.with_color_table_config(self.palette.color_table_config()),
+                        )?;
+                        blocks.encode(table)?;
+                    }
+                    None => blocks.encode(image_desc)?,
+                }
+
+                let mut image_data = block::ImageData::new(w * h);
+                if interlace {
+                    for y in interlaced_rows(h) {
+                        image_data
+                            .data_mut()
+                            .extend_from_slice(&self.buffer[(y * w)..((y + 1) * w)]);
+                    }
+                } else {
+                    image_data
+                        .data_mut()
+                        .extend_from_slice(&self.buffer[..(w * h)]);
+                }
+                blocks.encode(image_data)?;
+            }
+            Format::Apng => {
+                apng::encode_frame_control(
+                    output,
+                    &apng::FrameControl {
+                        sequence_number: self.sequence_number,
+                        left,
+                        top,
+                        width: w,
+                        height: h,
+                        delay_ms: delay.unwrap_or(0),
+                        dispose_op: apng::DisposeOp::None,
+                        blend_op: if first {
+                            apng::BlendOp::Source
+                        } else {
+                            apng::BlendOp::Over
+                        },
+                    },
+                );
+                self.sequence_number += 1;
+
+                let sequence_number = if first {
+                    None
+                } else {
+                    self.sequence_number += 1;
+                    Some(self.sequence_number - 1)
+                };
+                apng::encode_image_data(output, sequence_number, w, &self.buffer[..(w * h)]);
+            }
+            Format::Webp => {
+                self.webp.as_mut().expect("webp preamble").add_frame(
+                    ((left, top), (w, h)),
+                    &self.buffer[..(w * h)],
+                    self.palette.global_color_table().colors(),
+                    self.theme.transparent_color(),
+                    delay.unwrap_or(0),
+                    !first,
+                )?;
+            }
+            Format::Png => {
+                if first {
+                    let palette = self.palette.global_color_table().colors();
+                    let rgb: Vec<u8> = self.buffer[..(w * h)]
+                        .iter()
+                        .flat_map(|&idx| &palette[usize::from(idx) * 3..][..3])
+                        .copied()
+                        .collect();
+                    apng::encode_image_data(output, None, w * 3, &rgb);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_trailer(&mut self, output: &mut BytesMut) -> Result<(), RenderError> {
+        match self.format {
+            Format::Gif => {
+                Encoder::new(output.writer())
+                    .into_block_enc()
+                    .encode(block::Trailer::default())?;
+            }
+            Format::Apng | Format::Png => apng::encode_trailer(output),
+            Format::Webp => self.webp.take().expect("webp preamble").encode(output),
+        }
+        Ok(())
+    }
+}
+
+impl Render {
+    /// Renders the next part of the output. Returns `false` once complete.
+    fn render_next(&mut self, output: &mut BytesMut) -> Result<bool, RenderError> {
+        match &self.state {
+            RenderState::Cached(image) => {
+                output.extend_from_slice(image);
+                self.state = RenderState::Complete;
+                return Ok(true);
+            }
+            RenderState::Preamble => {
+                let num_frames = self.frames.len().max(1) + usize::from(self.kork);
+                self.encode_preamble(output, num_frames)?;
+            }
+            RenderState::Frame(_) => (),
+            RenderState::Complete => return Ok(false),
+        }
+
+        match self.step()? {
+            Some(step) => self.encode_step(output, &step)?,
+            None => {
+                // Add a black frame at the end, to work around twitter
+                // cutting off the last frame.
+                if self.kork {
+                    let height = self.height();
+                    let width = self.width();
+                    self.buffer[..(height * width)].fill(self.palette.background());
+                    self.encode_frame(
+                        output,
+                        Rect {
+                            left: 0,
+                            top: 0,
+                            width,
+                            height,
+                        },
+                        Some(KORK_DELAY),
+                        false,
+                    )?;
+                }
+
+                self.encode_trailer(output)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Encodes a frame just rendered into `buffer` by `step()`.
+    fn encode_step(&mut self, output: &mut BytesMut, step: &Step) -> Result<(), RenderError> {
+        if step.first {
+            self.encode_frame(output, step.rect, step.delay, true)
+        } else if step.regions.len() > 1 {
+            self.encode_regions(output, step.rect, &step.regions, step.delay)
+        } else {
+            self.encode_frame(output, step.rect, step.delay, false)
+        }
+    }
+
+    /// Advances to the next frame and renders its changes into `buffer`.
+    /// Returns `None` once all frames are rendered.
+    fn step(&mut self) -> Result<Option<Step>, RenderError> {
+        let prev = match mem::replace(&mut self.state, RenderState::Complete) {
+            RenderState::Preamble => None,
+            RenderState::Frame(prev) => Some(prev),
+            RenderState::Cached(_) | RenderState::Complete => return Ok(None),
+        };
+        let mut frame = match self.frames.next() {
+            Some(frame) => frame,
+            None if prev.is_none() => RenderFrame::default(),
+            None => return Ok(None),
+        };
+        frame.banner = self.banner.is_some() && self.frames.is_empty();
+
+        let (rect, regions) = match self.second.clone() {
+            Some(second) if prev.is_none() => self.render_two_up(&frame, &second)?,
+            _ => {
+                let keep = self.disposal == Disposal::Keep;
+                self.render_diff(prev.as_deref().filter(|_| keep), &frame)?
+            }
+        };
+        let step = Step {
+            rect,
+            regions,
+            delay: frame.delay,
+            first: prev.is_none(),
+        };
+        self.state = RenderState::Frame(Box::new(frame));
+        Ok(Some(step))
+    }
+
+    /// Renders two boards side by side into `buffer`, each with its own
+    /// orientation and highlights, and returns the full screen.
+    fn render_two_up(
+        &mut self,
+        frame: &RenderFrame,
+        second: &RenderFrame,
+    ) -> Result<(Rect, Vec<Rect>), RenderError> {
+        let (width, height) = (self.width(), self.height());
+        let mut screen = vec![self.palette.background(); width * height];
+        for (offset, frame) in [(0, frame), (self.panel_width() + self.two_up_gap(), second)] {
+            let (rect, _) = self.render_diff(None, frame)?;
+            for (y, row) in self.buffer[..(rect.width * rect.height)]
+                .chunks(rect.width)
+                .enumerate()
+            {
+                let start = (rect.top + y) * width + offset + rect.left;
+                screen[start..(start + rect.width)].copy_from_slice(row);
+            }
+        }
+        self.buffer[..(width * height)].copy_from_slice(&screen);
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            width,
+            height,
+        };
+        Ok((rect, vec![rect]))
+    }
+
+    /// Encodes parts of the image in `buffer`, which covers `rect`, as
+    /// separate image blocks. Only the last block has the delay of the
+    /// frame.
+    fn encode_regions(
+        &mut self,
+        output: &mut BytesMut,
+        rect: Rect,
+        regions: &[Rect],
+        delay: Option<u32>,
+    ) -> Result<(), RenderError> {
+        let images: Vec<Vec<u8>> = regions
+            .iter()
+            .map(|region| {
+                let region = region.relative_to(rect);
+                (region.top..region.top + region.height)
+                    .flat_map(|y| {
+                        let start = y * rect.width + region.left;
+                        &self.buffer[start..start + region.width]
+                    })
+                    .copied()
+                    .collect()
+            })
+            .collect();
+
+        for (i, (&region, image)) in regions.iter().zip(images).enumerate() {
+            self.buffer[..image.len()].copy_from_slice(&image);
+            let last = i + 1 == regions.len();
+            self.encode_frame(output, region, delay.filter(|_| last), false)?;
+        }
+        Ok(())
+    }
+
+    /// Renders the changes from `prev` to `frame` into `buffer`, and
+    /// returns the rectangle on screen that it covers. If splitting
+    /// regions, also returns the smaller rectangles that contain all
+    /// changes, otherwise just the same rectangle.
+    fn render_diff(
+        &mut self,
+        prev: Option<&RenderFrame>,
+        frame: &RenderFrame,
+    ) -> Result<(Rect, Vec<Rect>), RenderError> {
+        let theme = self.theme;
+        let background = self.palette.background();
+        let text = self.palette.text();
+        let orientation = frame.orientation;
+        // After a flip, nothing of the previous frame can be reused.
+        let prev = prev.filter(|prev| prev.orientation == orientation);
+        let bars = self.bars.as_ref();
+        let squares = self.squares;
+        let mirror = self.mirror;
+        let x = |sq| screen_x(orientation, mirror, sq);
+        let visible = |sq| squares.contains(x(sq), orientation.y(sq));
+        let diff: Bitboard = prev
+            .map_or(Bitboard::FULL, |p| p.diff(frame))
+            .into_iter()
+            .filter(|&sq| visible(sq))
+            .collect();
+
+        let (board_left, board_top) = (self.margin(), self.board_top());
+        let (board_width, board_height) = (self.board_width(), self.board_height());
+        let framed_width = self.framed_width();
+        let board_rect = Rect {
+            left: board_left,
+            top: board_top,
+            width: board_width,
+            height: board_height,
+        };
+        let square_rect = |sq| Rect {
+            left: board_left + (x(sq) - squares.left) * theme.square(),
+            top: board_top + (orientation.y(sq) - squares.top) * theme.square(),
+            width: theme.square(),
+            height: theme.square(),
+        };
+
+        let mut changed: Vec<Rect> = diff.into_iter().map(square_rect).collect();
+        let mut dirty = changed.iter().copied().reduce(Rect::union);
+
+        let mut bar_updates = Vec::with_capacity(4);
+        if let Some(bars) = bars {
+            for (top, (_, color)) in [
+                (0, bars.top(orientation)),
+                (
+                    board_top + board_height + board_left + self.pocket_height(),
+                    bars.bottom(orientation),
+                ),
+            ] {
+                let clock = frame.clock_state(color);
+                let material = bars.material.then(|| frame.material_lead(color));
+                let mut updates = Vec::with_capacity(2);
+                match prev {
+                    None => updates.push((
+                        Rect {
+                            left: 0,
+                            top,
+                            width: framed_width,
+                            height: theme.bar_height(),
+                        },
+                        BarUpdate::Full,
+                    )),
+                    Some(prev) => {
+                        if bars.turn_indicator && prev.turn != frame.turn {
+                            updates.push((turn_rect(theme, top), BarUpdate::Turn));
+                        }
+                        if bars.clocks && prev.clock_state(color) != clock {
+                            updates.push((clock_rect(theme, framed_width, top), BarUpdate::Clock));
+                        }
+                        if bars.material && Some(prev.material_lead(color)) != material {
+                            updates.push((
+                                material_rect(theme, framed_width, top, bars.clocks),
+                                BarUpdate::Material,
+                            ));
+                        }
+                    }
+                }
+                for (rect, update) in updates {
+                    dirty = Some(dirty.map_or(rect, |d| d.union(rect)));
+                    changed.push(rect);
+                    bar_updates.push((rect, update, color, clock, material.clone()));
+                }
+            }
+        }
+
+        // Labels only change with the orientation, after which everything
+        // is redrawn anyway.
+        let (rank_margin, file_margin) = (
+            Rect {
+                left: 0,
+                top: board_top,
+                width: board_left,
+                height: board_height + board_left,
+            },
+            Rect {
+                left: board_left,
+                top: board_top + board_height,
+                width: board_width,
+                height: board_left,
+            },
+        );
+        let margin_update = self.coordinate_margin && prev.is_none();
+        if margin_update {
+            for rect in [rank_margin, file_margin] {
+                dirty = Some(dirty.map_or(rect, |d| d.union(rect)));
+                changed.push(rect);
+            }
+        }
+
+        let mut pocket_updates = Vec::with_capacity(2);
+        if self.pockets {
+            let pocket_height = self.pocket_height();
+            for (top, color) in [
+                (
+                    board_top - pocket_height,
+                    orientation.fold(Color::Black, Color::White),
+                ),
+                (
+                    board_top + board_height + board_left,
+                    orientation.fold(Color::White, Color::Black),
+                ),
+            ] {
+                if !prev.is_some_and(|prev| prev.pocket(color) == frame.pocket(color)) {
+                    let rect = Rect {
+                        left: 0,
+                        top,
+                        width: framed_width,
+                        height: pocket_height,
+                    };
+                    dirty = Some(dirty.map_or(rect, |d| d.union(rect)));
+                    changed.push(rect);
+                    pocket_updates.push((rect, color));
+                }
+            }
+        }
+
+        // The caption strip spans below the eval bar and the move list.
+        let panel_height = self.height() - self.caption_height();
+        let eval_rect = Rect {
+            left: framed_width,
+            top: 0,
+            width: theme.eval_bar_width(),
+            height: panel_height,
+        };
+        let eval_update = self.eval_bar && !prev.is_some_and(|prev| prev.eval == frame.eval);
+        if eval_update {
+            dirty = Some(dirty.map_or(eval_rect, |d| d.union(eval_rect)));
+            changed.push(eval_rect);
+        }
+
+        let move_list_rect = Rect {
+            left: framed_width + self.eval_bar_width(),
+            top: 0,
+            width: self.move_list_width(),
+            height: panel_height,
+        };
+        let move_list_update =
+            self.move_list.is_some() && prev.filter(|prev| prev.moves == frame.moves).is_none();
+        if move_list_update {
+            dirty = Some(dirty.map_or(move_list_rect, |d| d.union(move_list_rect)));
+            changed.push(move_list_rect);
+        }
+
+        let caption_rect = Rect {
+            left: 0,
+            top: panel_height,
+            width: self.panel_width(),
+            height: self.caption_height(),
+        };
+        let caption_update =
+            self.captions && prev.filter(|prev| prev.caption == frame.caption).is_none();
+        if caption_update {
+            dirty = Some(dirty.map_or(caption_rect, |d| d.union(caption_rect)));
+            changed.push(caption_rect);
+        }
+
+        let dirty = dirty.unwrap_or(Rect {
+            left: board_left,
+            top: board_top,
+            width: theme.square(),
+            height: theme.square(),
+        });
+
+        let mut view =
+            ArrayViewMut2::from_shape((dirty.height, dirty.width), &mut self.buffer[..])?;
+
+        if prev.is_some() {
+            view.fill(theme.transparent_color());
+        }
+
+        let palette = &self.palette;
+        let blindfold = self.blindfold;
+        let rotate_opponent_pieces = self.rotate_opponent_pieces;
+        let grid = self.grid.then(|| theme.med_text_color());
+        let render_square = |view: &mut ArrayViewMut2<u8>, frame: &RenderFrame, sq: Square| {
+            let highlight = frame.highlighted.contains(sq);
+            let premove = !highlight && frame.premoved.contains(sq);
+            let muted = if highlight || premove {
+                None
+            } else {
+                frame.trail.iter().position(|squares| squares.contains(sq))
+            };
+            let key = SpriteKey {
+                piece: if blindfold {
+                    None
+                } else {
+                    frame.board.piece_at(sq)
+                },
+                dark_square: sq.is_dark(),
+                highlight: highlight || premove || muted.is_some(),
+                check: frame.checked.contains(sq),
+                mate: frame.mate,
+            };
+            let rotated = rotate_opponent_pieces
+                && key
+                    .piece
+                    .is_some_and(|piece| Orientation::from_color(piece.color) != frame.orientation);
+
+            if rotated {
+                view.assign(&theme.rotated_sprite(key));
+            } else {
+                view.assign(&theme.sprite(key));
+            }
+
+            let recolor = if highlight {
+                palette.recolor_highlight(sq.is_dark())
+            } else if premove {
+                palette.recolor_premove(sq.is_dark())
+            } else if let Some(age) = muted {
+                palette.recolor_muted(sq.is_dark(), age)
+            } else {
+                None
+            };
+            if let Some((from, to)) = recolor {
+                view.mapv_inplace(|c| if c == from { to } else { c });
+            }
+
+            let piece_mask = || {
+                theme.piece_mask(key).map(|mask| {
+                    if rotated {
+                        mask.slice(s![..;-1, ..;-1]).to_owned()
+                    } else {
+                        mask
+                    }
+                })
+            };
+
+            // Shadows are clipped to the square, so that neighbors do not
+            // need to be repainted.
+            if let Some(shadow) = palette.shadow_color(sq.is_dark()) {
+                if let Some(mask) = piece_mask() {
+                    let offset = theme.shadow_offset();
+                    for ((y, x), c) in view.indexed_iter_mut() {
+                        if y >= offset
+                            && x >= offset
+                            && mask[(y - offset, x - offset)]
+                            && !mask[(y, x)]
+                        {
+                            *c = shadow;
+                        }
+                    }
+                }
+            }
+
+            // Squares on the ray are empty, so the tint can cover them.
+            if frame.check_ray.contains(sq) {
+                let color = palette.brush_color(Brush::Red);
+                for ((y, x), c) in view.indexed_iter_mut() {
+                    if BAYER[y % 4][x % 4] < CHECK_RAY_OPACITY {
+                        *c = color;
+                    }
+                }
+            }
+
+            // Only the background is tinted, so that pieces stay legible.
+            if frame.overlay.contains(sq) {
+                let color = palette.brush_color(Brush::Yellow);
+                let mask = piece_mask();
+                for ((y, x), c) in view.indexed_iter_mut() {
+                    if BAYER[y % 4][x % 4] < OVERLAY_OPACITY
+                        && !mask.as_ref().is_some_and(|mask| mask[(y, x)])
+                    {
+                        *c = color;
+                    }
+                }
+            }
+
+            if frame.targets.contains(sq) {
+                render_target(view, key.piece.is_some(), palette.brush_color(Brush::Green));
+            }
+
+            // Each square draws the lines along its top and left edges, unless
+            // they are the edges of the visible board, so that repainted
+            // squares bring their part of the grid along.
+            if let Some(color) = grid {
+                let x = screen_x(frame.orientation, mirror, sq);
+                if x > squares.left {
+                    view.column_mut(0).fill(color);
+                }
+                if frame.orientation.y(sq) > squares.top {
+                    view.row_mut(0).fill(color);
+                }
+            }
+        };
+
+        for sq in diff {
+            let mut square_view = slice_rect(&mut view, square_rect(sq).relative_to(dirty));
+            render_square(&mut square_view, frame, sq);
+
+            if let Some(fade) = frame.fade.as_ref().filter(|fade| fade.squares.contains(sq)) {
+                let mut old = Array2::zeros((theme.square(), theme.square()));
+                let old_sq = if fade.from.orientation == orientation {
+                    sq
+                } else {
+                    sq.rotate_180()
+                };
+                render_square(&mut old.view_mut(), &fade.from, old_sq);
+                for ((y, x), c) in square_view.indexed_iter_mut() {
+                    if !fade.shows_new(x, y) {
+                        *c = old[(y, x)];
+                    }
+                }
+            }
+
+            if self.coordinates {
+                render_coordinates(
+                    &mut square_view,
+                    theme,
+                    sq,
+                    x(sq) == squares.left,
+                    orientation.y(sq) == squares.top + squares.height - 1,
+                );
+            }
+        }
+
+        for annotation in &frame.annotations {
+            if !visible(annotation.from) || !visible(annotation.to) {
+                continue;
+            }
+            render_annotation(
+                &mut view,
+                dirty,
+                square_rect(annotation.from),
+                square_rect(annotation.to),
+                self.palette.brush_color(annotation.brush),
+                annotation.weight,
+            );
+        }
+
+        if let Some((sq, piece)) = frame.promotion.filter(|&(sq, _)| visible(sq) && !blindfold) {
+            let mut cell = Array2::zeros((theme.square(), theme.square()));
+            render_square(
+                &mut cell.view_mut(),
+                &RenderFrame {
+                    board: Board::from_iter([(sq, piece)]),
+                    ..frame.clone()
+                },
+                sq,
+            );
+            let rect = promotion_rect(square_rect(sq), board_rect);
+            if let Some(overlap) = rect.intersection(dirty) {
+                let from = overlap.relative_to(rect);
+                let mut overlap_view = slice_rect(&mut view, overlap.relative_to(dirty));
+                for ((y, x), c) in overlap_view.indexed_iter_mut() {
+                    *c = cell[((from.top + y) / 2, (from.left + x) / 2)];
+                }
+            }
+        }
+
+        // Squares below the watermark may have been repainted, so draw it
+        // again wherever it overlaps.
+        if let Some(watermark) = &self.watermark {
+            let (height, width) = watermark.dim();
+            let rect = Rect {
+                left: board_left + board_width - width,
+                top: board_top + board_height - height,
+                width,
+                height,
+            };
+            if let Some(overlap) = rect.intersection(dirty) {
+                let from = overlap.relative_to(rect);
+                slice_rect(&mut view, overlap.relative_to(dirty)).assign(&watermark.slice(s!(
+                    from.top..(from.top + from.height),
+                    from.left..(from.left + from.width)
+                )));
+            }
+        }
+
+        if let Some(banner) = self.banner.as_ref().filter(|_| frame.banner) {
+            let (height, width) = banner.dim();
+            let rect = Rect {
+                left: board_left + (board_width - width) / 2,
+                top: board_top + (board_height - height) / 2,
+                width,
+                height,
+            };
+            if let Some(overlap) = rect.intersection(dirty) {
+                let from = overlap.relative_to(rect);
+                slice_rect(&mut view, overlap.relative_to(dirty)).assign(&banner.slice(s!(
+                    from.top..(from.top + from.height),
+                    from.left..(from.left + from.width)
+                )));
+            }
+        }
+
+        for (rect, update, color, clock, material) in bar_updates {
+            let mut rect_view = slice_rect(&mut view, rect.relative_to(dirty));
+            match update {
+                BarUpdate::Full => {
+                    if let Some(bars) = bars {
+                        render_bar(rect_view, theme, (background, text), bars, frame, color);
+                    }
+                }
+                BarUpdate::Clock => match clock {
+                    Some(clock) => render_clock(rect_view, theme, (background, text), clock),
+                    None => rect_view.fill(background),
+                },
+                BarUpdate::Material => match material {
+                    Some(ref material) => {
+                        render_material(rect_view, theme, background, material, color)
+                    }
+                    None => rect_view.fill(background),
+                },
+                BarUpdate::Turn => render_turn(rect_view, (background, text), frame.turn == color),
+            }
+        }
+
+        if margin_update {
+            let ranks = (squares.top..squares.top + squares.height)
+                .map(|y| Rank::new(orientation.fold(7 - y, y) as u32).char());
+            render_margin(
+                slice_rect(&mut view, rank_margin.relative_to(dirty)),
+                theme,
+                (background, text),
+                ranks,
+                true,
+            );
+            let files = (squares.left..squares.left + squares.width)
+                .map(|x| if mirror { 7 - x } else { x })
+                .map(|x| File::new(orientation.fold(x, 7 - x) as u32).char());
+            render_margin(
+                slice_rect(&mut view, file_margin.relative_to(dirty)),
+                theme,
+                (background, text),
+                files,
+                false,
+            );
+        }
+
+        for (rect, color) in pocket_updates {
+            render_pocket(
+                slice_rect(&mut view, rect.relative_to(dirty)),
+                theme,
+                background,
+                frame.pocket(color),
+                color,
+            );
+        }
+
+        if eval_update {
+            render_eval_bar(
+                slice_rect(&mut view, eval_rect.relative_to(dirty)),
+                theme,
+                background,
+                orientation,
+                (board_top, board_height),
+                frame.eval_ratio(),
+            );
+        }
+
+        if let Some(move_list) = self.move_list.as_ref().filter(|_| move_list_update) {
+            render_move_list(
+                slice_rect(&mut view, move_list_rect.relative_to(dirty)),
+                theme,
+                (background, text),
+                &move_list[..frame.moves],
+            );
+        }
+
+        if caption_update {
+            render_caption(
+                slice_rect(&mut view, caption_rect.relative_to(dirty)),
+                theme,
+                (background, text),
+                frame
+                    .caption
+                    .as_ref()
+                    .map_or("", |caption| caption.as_str()),
+            );
+        }
+
+        let regions = if self.split_regions && prev.is_some() && !changed.is_empty() {
+            connected(changed)
+        } else {
+            vec![dirty]
+        };
+        Ok((dirty, regions))
+    }
+
+    /// Renders the changes of the next frame, like streaming would, but
+    /// without encoding them. Returns `None` once all frames are rendered.
+    /// Meant for benchmarks that measure rendering separately from
+    /// encoding.
+    #[cfg(feature = "bench")]
+    pub fn render_next_diff(&mut self) -> Result<Option<RenderedFrame>, RenderError> {
+        Ok(self.step()?.map(RenderedFrame))
+    }
+
+    /// Encodes the frame most recently returned by `render_next_diff()`
+    /// into `output`. Only the image data is written, so the output is
+    /// not a complete image. Not for WebP, which needs the preamble.
+    #[cfg(feature = "bench")]
+    pub fn encode_rendered(
+        &mut self,
+        output: &mut BytesMut,
+        frame: &RenderedFrame,
+    ) -> Result<(), RenderError> {
+        self.encode_step(output, &frame.0)
+    }
+
+    /// Width and height of the output in pixels, including player bars,
+    /// pockets and sidebars. Known as soon as the render is constructed.
+    pub fn dimensions(&self) -> (u16, u16) {
+        (self.width() as u16, self.height() as u16)
+    }
+
+    /// Number of frames in the output, including the black end frame.
+    /// Meant to be called before streaming. `None` for frames pulled
+    /// lazily, whose number is not known in advance.
+    pub fn frame_count(&self) -> Option<usize> {
+        match self.frames {
+            Frames::Vec(ref frames) => Some(frames.len().max(1) + usize::from(self.kork)),
+            Frames::Lazy { .. } => None,
+        }
+    }
+
+    /// Sum of all frame delays in centiseconds, including the black end
+    /// frame. Meant to be called before streaming. `None` for frames pulled
+    /// lazily.
+    pub fn total_duration_cs(&self) -> Option<u64> {
+        match self.frames {
+            Frames::Vec(ref frames) => {
+                let ms: u64 = frames
+                    .as_slice()
+                    .iter()
+                    .filter_map(|frame| frame.delay)
+                    .chain(self.kork.then_some(KORK_DELAY))
+                    .map(u64::from)
+                    .sum();
+                Some((ms + 5) / 10)
+            }
+            Frames::Lazy { .. } => None,
+        }
+    }
+
+    /// Renders only the first frame as a standalone still image, without
+    /// advancing this render, so it should be called before streaming.
+    /// While streaming, the most recently rendered frame is used instead.
+    /// Posters of animated PNGs are plain PNGs.
+    pub fn poster(&self) -> Result<Bytes, RenderError> {
+        let frame = match self.state {
+            RenderState::Cached(ref image) => return Ok(image.clone()),
+            RenderState::Frame(ref frame) => Some(frame.as_ref()),
+            RenderState::Preamble | RenderState::Complete => self.frames.first(),
+        };
+        let poster = Render {
+            theme: self.theme,
+            palette: self.palette.clone(),
+            state: RenderState::Preamble,
+            buffer: Vec::new(),
+            comment: self.comment,
+            ascii_comments: self.ascii_comments,
+            metadata: None,
+            embedded_fen: None,
+            bars: self.bars.clone(),
+            coordinates: self.coordinates,
+            coordinate_margin: self.coordinate_margin,
+            blindfold: self.blindfold,
+            rotate_opponent_pieces: self.rotate_opponent_pieces,
+            mirror: self.mirror,
+            grid: self.grid,
+            density: self.density,
+            watermark: self.watermark.clone(),
+            banner: self.banner.clone(),
+            squares: self.squares,
+            pockets: self.pockets,
+            eval_bar: self.eval_bar,
+            move_list: self.move_list.clone(),
+            captions: self.captions,
+            frames: Frames::Vec(
+                frame
+                    .map(|frame| RenderFrame {
+                        fade: None,
+                        ..frame.clone()
+                    })
+                    .into_iter()
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            ),
+            second: self.second.clone(),
+            transparent_background: self.transparent_background,
+            kork: false,
+            split_regions: false,
+            interlace: self.interlace,
+            disposal: Disposal::Keep,
+            loops: NonZeroU16::new(1),
+            format: match self.format {
+                Format::Apng => Format::Png,
+                format => format,
+            },
+            sequence_number: 0,
+            delay_error: 0,
+            webp: None,
+            cache: None,
+            output: BytesMut::new(),
+        }
+        .with_buffer();
+
+        let mut image = BytesMut::new();
+        for part in poster {
+            image.extend_from_slice(&part?);
+        }
+        Ok(image.freeze())
+    }
+
+    /// Yields the frames as color indices instead of encoded parts, for
+    /// encoders other than the built-in ones. The format of the render is
+    /// ignored.
+    pub fn into_raw_frames(mut self) -> RawFrames {
+        if let RenderState::Cached(_) = self.state {
+            // The cache only holds encoded images.
+            self.state = RenderState::Preamble;
+            self = self.with_buffer();
+        }
+        self.cache = None;
+        RawFrames { render: self }
+    }
+
+    /// Panics on render errors instead of yielding them.
+    pub fn infallible(self) -> impl Iterator<Item = Bytes> {
+        self.map(|part| part.expect("render"))
+    }
+
+    /// Writes the whole output into `writer`, part by part as iterating
+    /// would, but reusing a single buffer instead of handing out `Bytes`.
+    /// Render errors are reported as `io::ErrorKind::Other`.
+    pub fn write_all(mut self, writer: &mut impl Write) -> io::Result<()> {
+        let mut output = mem::take(&mut self.output);
+        loop {
+            output.clear();
+            match self.render_next(&mut output) {
+                Ok(true) => {
+                    if let Some(cache) = &mut self.cache {
+                        cache.image.extend_from_slice(&output);
+                    }
+                    writer.write_all(&output)?;
+                }
+                Ok(false) => {
+                    self.finish_cache();
+                    return Ok(());
+                }
+                Err(err) => return Err(io::Error::other(err)),
+            }
+        }
+    }
+
+    /// Stores the completed image, if it was rendered for the cache.
+    fn finish_cache(&mut self) {
+        if let Some(writer) = self.cache.take() {
+            writer.cache.insert(writer.key, writer.image.freeze());
+        }
+    }
+}
+
+impl Iterator for Render {
+    type Item = Result<Bytes, RenderError>;
+
+    fn next(&mut self) -> Option<Result<Bytes, RenderError>> {
+        let mut output = mem::take(&mut self.output);
+        output.clear();
+        // Formats that can not be streamed may not produce output for every
+        // frame.
+        while output.is_empty() {
+            match self.render_next(&mut output) {
+                Ok(true) => (),
+                Ok(false) => {
+                    self.finish_cache();
+                    return None;
+                }
+                Err(err) => {
+                    self.state = RenderState::Complete;
+                    self.cache = None;
+                    return Some(Err(err));
+                }
+            }
+        }
+        if let Some(writer) = &mut self.cache {
+            writer.image.extend_from_slice(&output);
+        }
+        let part = output.split().freeze();
+        self.output = output;
+        Some(Ok(part))
+    }
+}
+
+impl FusedIterator for Render {}
+
+/// Frames of a render as indices into a shared palette. Created by
+/// `Render::into_raw_frames()`.
+pub struct RawFrames {
+    render: Render,
+}
+
+/// The changed rectangle of a frame.
+pub struct RawFrame {
+    pub left: usize,
+    pub top: usize,
+    pub width: usize,
+    pub height: usize,
+    /// Color indices, row by row.
+    pub indices: Vec<u8>,
+    /// Index of pixels that keep showing the previous frame. Not used in
+    /// the first frame, which covers the whole image.
+    pub transparent: Option<u8>,
+    /// Delay in milliseconds.
+    pub delay: Option<u32>,
+}
+
+impl RawFrames {
+    /// Colors of the palette, as RGB triples.
+    pub fn palette(&self) -> &[u8] {
+        self.render.palette.global_color_table().colors()
+    }
+
+    pub fn dimensions(&self) -> (u16, u16) {
+        self.render.dimensions()
+    }
+}
+
+impl Iterator for RawFrames {
+    type Item = Result<RawFrame, RenderError>;
+
+    fn next(&mut self) -> Option<Result<RawFrame, RenderError>> {
+        let render = &mut self.render;
+        let transparent = render.theme.transparent_color();
+        render.step().transpose().map(|step| {
+            step.map(|step| RawFrame {
+                left: step.rect.left,
+                top: step.rect.top,
+                width: step.rect.width,
+                height: step.rect.height,
+                indices: render.buffer[..(step.rect.width * step.rect.height)].to_vec(),
+                transparent: (!step.first).then_some(transparent),
+                delay: step.delay,
+            })
+        })
+    }
+}
+
+impl FusedIterator for RawFrames {}
+
+/// Column of a square on screen, with the files reversed if `mirror`.
+fn screen_x(orientation: Orientation, mirror: bool, square: Square) -> usize {
+    let x = orientation.x(square);
+    if mirror {
+        7 - x
+    } else {
+        x
+    }
+}
+
+/// Sets the disposal method of a graphic control extension.
+/// `set_disposal_method()` of gift also sets all other flags, including
+/// transparency, which output with the default disposal has always had,
+/// so only the other methods set the bits directly.
+fn set_disposal(ctrl: &mut block::GraphicControl, disposal: Disposal) {
+    match disposal {
+        Disposal::Keep => ctrl.set_disposal_method(block::DisposalMethod::Keep),
+        Disposal::Background => ctrl.set_flags(ctrl.flags() | 2 << 2),
+        Disposal::Previous => ctrl.set_flags(ctrl.flags() | 3 << 2),
+    }
+}
+
+/// Rows of an image in the order of GIF interlacing: every 8th row
+/// starting at 0, every 8th starting at 4, every 4th starting at 2, and
+/// finally every 2nd starting at 1.
+fn interlaced_rows(height: usize) -> impl Iterator<Item = usize> {
+    [(0, 8), (4, 8), (2, 4), (1, 2)]
+        .into_iter()
+        .flat_map(move |(start, step)| (start..height).step_by(step))
+}
+
+/// Builds a comment extension. Text may not fit in a single sub-block of
+/// at most 255 bytes, so it is split into several, which readers
+/// concatenate. `gift` would panic on longer sub-blocks. Splits are on
+/// character boundaries, so that readers that show sub-blocks one by one
+/// do not get broken UTF-8.
+fn gif_comment(text: &str) -> block::Comment {
+    let mut comment = block::Comment::default();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(255);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, tail) = rest.split_at(end);
+        comment.add_comment(chunk.as_bytes());
+        rest = tail;
+    }
+    comment
+}
+
+/// Replaces characters outside of 7-bit ASCII with `?`, if `ascii`.
+fn ascii_text(text: &str, ascii: bool) -> Cow<'_, str> {
+    if ascii && !text.is_ascii() {
+        Cow::Owned(
+            text.chars()
+                .map(|c| if c.is_ascii() { c } else { '?' })
+                .collect(),
+        )
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+const TITLES: [&str; 12] = [
+    "GM", "WGM", "IM", "WIM", "FM", "WFM", "NM", "CM", "WCM", "WNM", "LM", "BOT",
+];
+
+fn render_bar(
+    mut view: ArrayViewMut2<u8>,
+    theme: &Theme,
+    (background, text): (u8, u8),
+    bars: &PlayerBars,
+    frame: &RenderFrame,
+    color: Color,
+) {
+    view.fill(background);
+
+    let padding = theme.bar_height() as f32 / 6.0;
+    let scale = Scale::uniform(theme.bar_height() as f32 - 2.0 * padding);
+    let (_, width) = view.dim();
+    let mut left = padding;
+    let mut max_width = width as f32 - 2.0 * padding;
+
+    if bars.turn_indicator {
+        let rect = turn_rect(theme, 0);
+        render_turn(
+            view.slice_mut(s!(.., ..rect.width)),
+            (background, text),
+            frame.turn == color,
+        );
+        left = rect.width as f32;
+        max_width -= left - padding;
+    }
+
+    if let Some(clock) = frame.clock_state(color) {
+        let rect = clock_rect(theme, width, 0);
+        render_clock(
+            view.slice_mut(s!(.., rect.left..(rect.left + rect.width))),
+            theme,
+            (background, text),
+            clock,
+        );
+        max_width -= rect.width as f32;
+    }
+
+    if bars.material {
+        let rect = material_rect(theme, width, 0, bars.clocks);
+        render_material(
+            view.slice_mut(s!(.., rect.left..(rect.left + rect.width))),
+            theme,
+            background,
+            &frame.material_lead(color),
+            color,
+        );
+        max_width -= rect.width as f32;
+    }
+
+    if let Some(rating) = bars.player(color).rating {
+        let rating = format!("({})", rating);
+        let rating_width = text_width(theme, scale, &rating);
+        render_text(
+            &mut view,
+            theme,
+            scale,
+            left + max_width - rating_width,
+            padding,
+            &rating,
+            theme.med_text_color(),
+        );
+        max_width -= rating_width + padding;
+    }
+
+    let player_name = match bars.player(color).name {
+        Some(ref player_name) => player_name.as_str(),
+        None => return,
+    };
+
+    let (title, name) = match player_name.split_once(' ') {
+        Some((title, name)) if TITLES.contains(&title) => (Some(title), name),
+        _ => (None, player_name),
+    };
+
+    let mut x = left;
+    if let Some(title) = title {
+        let title_color = if title == "BOT" {
+            theme.bot_color()
+        } else {
+            theme.gold_color()
+        };
+        let title = format!("{} ", title);
+        let title = truncate_text(theme, scale, max_width, &title);
+        render_text(&mut view, theme, scale, x, padding, &title, title_color);
+        x += text_width(theme, scale, &title);
+    }
+
+    let name = truncate_text(theme, scale, max_width - (x - left), name);
+    render_text(&mut view, theme, scale, x, padding, &name, text);
+}
+
+fn render_clock(
+    mut view: ArrayViewMut2<u8>,
+    theme: &Theme,
+    (background, text_color): (u8, u8),
+    (centis, active): (u32, bool),
+) {
+    view.fill(background);
+
+    let padding = theme.bar_height() as f32 / 6.0;
+    let scale = Scale::uniform(theme.bar_height() as f32 - 2.0 * padding);
+
+    let seconds = centis / 100;
+    let text = if seconds >= 3600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    };
+
+    let left = view.dim().1 as f32 - padding - text_width(theme, scale, &text);
+    let color = if active {
+        text_color
+    } else {
+        theme.med_text_color()
+    };
+    render_text(&mut view, theme, scale, left, padding, &text, color);
+}
+
+/// Renders a dot if the side of the bar is to move, or clears it.
+fn render_turn(mut view: ArrayViewMut2<u8>, (background, text): (u8, u8), to_move: bool) {
+    view.fill(background);
+    if !to_move {
+        return;
+    }
+    let (height, width) = view.dim();
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+    let radius = height as f32 / 5.0;
+    for ((y, x), pixel) in view.indexed_iter_mut() {
+        if (x as f32 + 0.5 - cx).hypot(y as f32 + 0.5 - cy) <= radius {
+            *pixel = text;
+        }
+    }
+}
+
+/// Renders the eval bar next to the board, filled for white from the
+/// white side according to `ratio`.
+fn render_eval_bar(
+    mut view: ArrayViewMut2<u8>,
+    theme: &Theme,
+    background: u8,
+    orientation: Orientation,
+    (board_top, height): (usize, usize),
+    ratio: f32,
+) {
+    view.fill(background);
+    // Black keeps the dark bar color even on light bars.
+    view.slice_mut(s!(board_top..(board_top + height), ..))
+        .fill(theme.bar_color());
+    let white = (ratio * height as f32).round() as usize;
+    let (top, bottom) = orientation.fold((height - white, height), (0, white));
+    view.slice_mut(s!((board_top + top)..(board_top + bottom), ..))
+        .fill(theme.text_color());
+}
+
+/// Square area at the left of a bar reserved for the turn indicator, in
+/// screen coordinates.
+fn turn_rect(theme: &Theme, top: usize) -> Rect {
+    Rect {
+        left: 0,
+        top,
+        width: theme.bar_height(),
+        height: theme.bar_height(),
+    }
+}
+
+/// Right-aligned area of a bar of the given width reserved for the clock,
+/// in screen coordinates.
+fn clock_rect(theme: &Theme, width: usize, top: usize) -> Rect {
+    let padding = theme.bar_height() as f32 / 6.0;
+    let scale = Scale::uniform(theme.bar_height() as f32 - 2.0 * padding);
+    let clock_width = (text_width(theme, scale, "0:00:00") + 2.0 * padding).ceil() as usize;
+    Rect {
+        left: width.saturating_sub(clock_width),
+        top,
+        width: clock_width.min(width),
+        height: theme.bar_height(),
+    }
+}
+
+/// Area of a bar reserved for the material lead, left of the clock, in
+/// screen coordinates.
+fn material_rect(theme: &Theme, width: usize, top: usize, clocks: bool) -> Rect {
+    let right = if clocks {
+        clock_rect(theme, width, top).left
+    } else {
+        width
+    };
+    let material_width = (theme.square() * 3).min(right / 2);
+    Rect {
+        left: right - material_width,
+        top,
+        width: material_width,
+        height: theme.bar_height(),
+    }
+}
+
+/// Renders small icons of the opponent pieces that `color` has captured in
+/// excess, followed by the lead in points.
+fn render_material(
+    mut view: ArrayViewMut2<u8>,
+    theme: &Theme,
+    background: u8,
+    material: &MaterialLead,
+    color: Color,
+) {
+    view.fill(background);
+
+    let padding = theme.bar_height() as f32 / 6.0;
+    let size = (theme.bar_height() as f32 - 2.0 * padding) as usize;
+    let top = padding as usize;
+    let (_, width) = view.dim();
+
+    let mut x = padding as usize;
+    let mut prev_role = None;
+    for &role in &material.pieces {
+        if let Some(prev_role) = prev_role {
+            // Overlap pieces of the same kind.
+            x += if prev_role == role { size / 2 } else { size };
+        }
+        if x + size > width {
+            break;
+        }
+        render_icon(
+            &mut view,
+            theme,
+            Piece {
+                color: !color,
+                role,
+            },
+            (x, top),
+            size,
+        );
+        prev_role = Some(role);
+    }
+
+    if material.points > 0 {
+        let scale = Scale::uniform(size as f32);
+        let left = if prev_role.is_some() {
+            (x + size) as f32 + padding / 2.0
+        } else {
+            padding
+        };
+        let text = format!("+{}", material.points);
+        render_text(
+            &mut view,
+            theme,
+            scale,
+            left,
+            padding,
+            &text,
+            theme.med_text_color(),
+        );
+    }
+}
+
+/// Renders the pieces in a Crazyhouse pocket, each followed by its count
+/// if there are several.
+fn render_pocket(
+    mut view: ArrayViewMut2<u8>,
+    theme: &Theme,
+    background: u8,
+    pocket: Option<&ByRole<u8>>,
+    color: Color,
+) {
+    view.fill(background);
+
+    let pocket = match pocket {
+        Some(pocket) => pocket,
+        None => return,
+    };
+
+    let padding = theme.pocket_height() / 10;
+    let size = theme.pocket_height() - 2 * padding;
+    let scale = Scale::uniform(size as f32);
+    let (_, width) = view.dim();
+
+    let mut x = padding;
+    for role in [
+        Role::Pawn,
+        Role::Knight,
+        Role::Bishop,
+        Role::Rook,
+        Role::Queen,
+    ] {
+        let count = *pocket.get(role);
+        if count == 0 {
+            continue;
+        }
+        let text = if count > 1 {
+            count.to_string()
+        } else {
+            String::new()
+        };
+        let text_width = text_width(theme, scale, &text).ceil() as usize;
+        if x + size + text_width > width {
+            break;
+        }
+        render_icon(&mut view, theme, Piece { color, role }, (x, padding), size);
+        render_text(
+            &mut view,
+            theme,
+            scale,
+            (x + size) as f32,
+            padding as f32,
+            &text,
+            theme.med_text_color(),
+        );
+        x += size + text_width + 2 * padding;
+    }
+}
+
+/// Draws a piece scaled down to `size`, leaving the surrounding pixels
+/// untouched.
+fn render_icon(
+    view: &mut ArrayViewMut2<u8>,
+    theme: &Theme,
+    piece: Piece,
+    (left, top): (usize, usize),
+    size: usize,
+) {
+    let sprite = theme.sprite(SpriteKey {
+        piece: Some(piece),
+        dark_square: false,
+        highlight: false,
+        check: false,
+        mate: false,
+    });
+    let empty = theme.sprite(SpriteKey {
+        piece: None,
+        dark_square: false,
+        highlight: false,
+        check: false,
+        mate: false,
+    });
+    for y in 0..size {
+        for x in 0..size {
+            let (sy, sx) = (y * theme.square() / size, x * theme.square() / size);
+            // Compare with the empty square, which may be textured.
+            if sprite[(sy, sx)] != empty[(sy, sx)] {
+                view[(top + y, left + x)] = sprite[(sy, sx)];
+            }
+        }
+    }
+}
+
+fn text_width(theme: &Theme, scale: Scale, text: &str) -> f32 {
+    theme
+        .font()
+        .layout(text, scale, point(0.0, 0.0))
+        .last()
+        .map_or(0.0, |g| {
+            g.position().x + g.unpositioned().h_metrics().advance_width
+        })
+}
+
+fn truncate_text(theme: &Theme, scale: Scale, max_width: f32, text: &str) -> String {
+    if text_width(theme, scale, text) <= max_width {
+        return text.to_owned();
+    }
+    let mut truncated = text.to_owned();
+    while !truncated.is_empty() {
+        truncated.pop();
+        let candidate = format!("{}…", truncated.trim_end());
+        if text_width(theme, scale, &candidate) <= max_width {
+            return candidate;
+        }
+    }
+    String::new()
+}
+
+fn render_text(
+    view: &mut ArrayViewMut2<u8>,
+    theme: &Theme,
+    scale: Scale,
+    left: f32,
+    top: f32,
+    text: &str,
+    color: u8,
+) {
+    let (height, width) = view.dim();
+    let v_metrics = theme.font().v_metrics(scale);
+    let glyphs = theme
+        .font()
+        .layout(text, scale, point(left, top + v_metrics.ascent));
+
+    for g in glyphs {
+        if let Some(bb) = g.pixel_bounding_box() {
+            g.draw(|x, y, intensity| {
+                let x = x as i32 + bb.min.x;
+                let y = y as i32 + bb.min.y;
+                if x < 0 || y < 0 || x as usize >= width || y as usize >= height {
+                    return;
+                }
+                if intensity >= 0.5 {
+                    view[(y as usize, x as usize)] = color;
+                } else if intensity >= 0.1
+                    && (color == theme.text_color() || color == theme.bar_color())
+                {
+                    view[(y as usize, x as usize)] = theme.med_text_color();
+                }
+            });
+        }
+    }
+}
+
+/// Renders a caption like a player name, truncated to fit the strip.
+fn render_caption(
+    mut view: ArrayViewMut2<u8>,
+    theme: &Theme,
+    (background, text_color): (u8, u8),
+    caption: &str,
+) {
+    view.fill(background);
+
+    let padding = theme.bar_height() as f32 / 6.0;
+    let scale = Scale::uniform(theme.bar_height() as f32 - 2.0 * padding);
+    let width = view.dim().1 as f32;
+    let caption = truncate_text(theme, scale, width - 2.0 * padding, caption);
+    render_text(
+        &mut view, theme, scale, padding, padding, &caption, text_color,
+    );
+}
+
+/// Renders the most recent moves that fit, one per line, with the last
+/// move highlighted at the bottom once the list scrolls.
+fn render_move_list(
+    mut view: ArrayViewMut2<u8>,
+    theme: &Theme,
+    (background, text_color): (u8, u8),
+    moves: &[String],
+) {
+    view.fill(background);
+
+    let size = theme.square() as f32 / 4.0;
+    let scale = Scale::uniform(size);
+    let padding = theme.square() as f32 / 15.0;
+    let line_height = theme.square() / 3;
+    let (height, width) = view.dim();
+    let rows = height.saturating_sub(padding as usize) / line_height;
+
+    let first = moves.len().saturating_sub(rows);
+    for (row, text) in moves[first..].iter().enumerate() {
+        let current = first + row + 1 == moves.len();
+        let text = truncate_text(theme, scale, width as f32 - 2.0 * padding, text);
+        render_text(
+            &mut view,
+            theme,
+            scale,
+            padding,
+            padding + (row * line_height) as f32,
+            &text,
+            if current {
+                text_color
+            } else {
+                theme.med_text_color()
+            },
+        );
+    }
+}
+
+/// Renders a text label on the background color, at most `max_width`
+/// wide.
+fn render_watermark(
+    theme: &Theme,
+    background: u8,
+    text_color: u8,
+    text: &str,
+    max_width: usize,
+) -> Array2<u8> {
+    let size = theme.square() as f32 / 4.0;
+    let scale = Scale::uniform(size);
+    let padding = theme.square() as f32 / 15.0;
+    let text = truncate_text(theme, scale, max_width as f32 - 2.0 * padding, text);
+    let width = ((text_width(theme, scale, &text) + 2.0 * padding).ceil() as usize).min(max_width);
+    let height = (size + padding).ceil() as usize;
+    let mut watermark = Array2::from_elem((height, width), background);
+    render_text(
+        &mut watermark.view_mut(),
+        theme,
+        scale,
+        padding,
+        padding / 2.0,
+        &text,
+        text_color,
+    );
+    watermark
+}
+
+/// Renders the result of the game as a label for the center of the board,
+/// at most two squares high.
+fn render_banner(
+    theme: &Theme,
+    background: u8,
+    text_color: u8,
+    result: GameResult,
+    max_width: usize,
+) -> Array2<u8> {
+    let text = match result {
+        GameResult::WhiteWins => "1-0 · White wins",
+        GameResult::BlackWins => "0-1 · Black wins",
+        GameResult::Draw => "½-½ · Draw",
+    };
+    let size = theme.square() as f32 / 2.0;
+    let scale = Scale::uniform(size);
+    let padding = theme.square() as f32 / 4.0;
+    let width = ((text_width(theme, scale, text) + 2.0 * padding).ceil() as usize).min(max_width);
+    let height = (size + padding).ceil() as usize;
+    let mut banner = Array2::from_elem((height, width), background);
+    render_text(
+        &mut banner.view_mut(),
+        theme,
+        scale,
+        padding,
+        padding / 2.0,
+        text,
+        text_color,
+    );
+    banner
+}
+
+/// Labels the rank on squares at the `left` edge and the file on squares
+/// at the `bottom` edge of the visible board.
+fn render_coordinates(
+    view: &mut ArrayViewMut2<u8>,
+    theme: &Theme,
+    sq: Square,
+    left: bool,
+    bottom: bool,
+) {
+    let color = if sq.is_dark() {
+        theme.light_square_color()
+    } else {
+        theme.dark_square_color()
+    };
+    let size = theme.square() as f32 / 4.0;
+    let scale = Scale::uniform(size);
+    let padding = theme.square() as f32 / 30.0;
+
+    if left {
+        let rank = sq.rank().char().to_string();
+        render_text(view, theme, scale, padding, 0.0, &rank, color);
+    }
+
+    if bottom {
+        let file = sq.file().char().to_string();
+        let left = theme.square() as f32 - padding - text_width(theme, scale, &file);
+        let top = theme.square() as f32 - padding - size;
+        render_text(view, theme, scale, left, top, &file, color);
+    }
+}
+
+/// Renders coordinate labels into a margin next to the board, centered
+/// on consecutive squares, either from top to bottom or from left to
+/// right.
+fn render_margin(
+    mut view: ArrayViewMut2<u8>,
+    theme: &Theme,
+    (background, text_color): (u8, u8),
+    labels: impl Iterator<Item = char>,
+    vertical: bool,
+) {
+    view.fill(background);
+
+    let size = theme.square() as f32 / 4.0;
+    let scale = Scale::uniform(size);
+    let square = theme.square() as f32;
+    let margin = theme.coordinate_margin() as f32;
+    for (i, label) in labels.enumerate() {
+        let label = label.to_string();
+        let width = text_width(theme, scale, &label);
+        let (left, top) = if vertical {
+            (
+                (margin - width) / 2.0,
+                i as f32 * square + (square - size) / 2.0,
+            )
+        } else {
+            (
+                i as f32 * square + (square - width) / 2.0,
+                (margin - size) / 2.0,
+            )
+        };
+        render_text(&mut view, theme, scale, left, top, &label, text_color);
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Rect {
+    left: usize,
+    top: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Rect {
+    const BOARD: Rect = Rect {
+        left: 0,
+        top: 0,
+        width: 8,
+        height: 8,
+    };
+
+    fn square(x: usize, y: usize) -> Rect {
+        Rect {
+            left: x,
+            top: y,
+            width: 1,
+            height: 1,
+        }
+    }
+
+    fn contains(self, x: usize, y: usize) -> bool {
+        (self.left..self.left + self.width).contains(&x)
+            && (self.top..self.top + self.height).contains(&y)
+    }
+
+    fn union(self, other: Rect) -> Rect {
+        let left = self.left.min(other.left);
+        let top = self.top.min(other.top);
+        Rect {
+            left,
+            top,
+            width: (self.left + self.width).max(other.left + other.width) - left,
+            height: (self.top + self.height).max(other.top + other.height) - top,
+        }
+    }
+
+    /// Tests if the rectangles overlap or share an edge or corner.
+    fn touches(self, other: Rect) -> bool {
+        self.left <= other.left + other.width
+            && other.left <= self.left + self.width
+            && self.top <= other.top + other.height
+            && other.top <= self.top + self.height
+    }
+
+    fn intersection(self, other: Rect) -> Option<Rect> {
+        let left = self.left.max(other.left);
+        let top = self.top.max(other.top);
+        let right = (self.left + self.width).min(other.left + other.width);
+        let bottom = (self.top + self.height).min(other.top + other.height);
+        (left < right && top < bottom).then(|| Rect {
+            left,
+            top,
+            width: right - left,
+            height: bottom - top,
+        })
+    }
+
+    fn relative_to(self, outer: Rect) -> Rect {
+        Rect {
+            left: self.left - outer.left,
+            top: self.top - outer.top,
+            ..self
+        }
+    }
+}
+
+/// Merges touching rectangles into their bounding rectangles, until all
+/// remaining rectangles are apart.
+fn connected(mut rects: Vec<Rect>) -> Vec<Rect> {
+    let mut merged = Vec::with_capacity(rects.len());
+    while let Some(mut rect) = rects.pop() {
+        while let Some(i) = rects.iter().position(|other| rect.touches(*other)) {
+            rect = rect.union(rects.swap_remove(i));
+        }
+        merged.push(rect);
+    }
+    // Merging may have made earlier results touch later ones.
+    if merged
+        .iter()
+        .enumerate()
+        .any(|(i, a)| merged[i + 1..].iter().any(|b| a.touches(*b)))
+    {
+        connected(merged)
+    } else {
+        merged
+    }
+}
+
+fn slice_rect<'a>(view: &'a mut ArrayViewMut2<u8>, rect: Rect) -> ArrayViewMut2<'a, u8> {
+    view.slice_mut(s!(
+        rect.top..(rect.top + rect.height),
+        rect.left..(rect.left + rect.width)
+    ))
+}
+
+/// Rasterizes an annotation between two squares on screen into `view`,
+/// which covers `view_rect`. Pixels outside of `view_rect` are clipped.
+/// Arrows below `FULL_WEIGHT` are narrower and dithered.
+fn render_annotation(
+    view: &mut ArrayViewMut2<u8>,
+    view_rect: Rect,
+    from: Rect,
+    to: Rect,
+    color: u8,
+    weight: u8,
+) {
+    let missing = f32::from(FULL_WEIGHT - weight) / f32::from(FULL_WEIGHT);
+    let scale = 1.0 - (1.0 - MIN_ARROW_SCALE) * missing;
+    let (shaft_width, head_width, head_length) = (
+        ARROW_SHAFT_WIDTH * scale,
+        ARROW_HEAD_WIDTH * scale,
+        ARROW_HEAD_LENGTH * scale,
+    );
+    let opacity = (u16::from(MIN_ARROW_OPACITY) * u16::from(FULL_WEIGHT)
+        + u16::from(16 - MIN_ARROW_OPACITY) * u16::from(weight))
+        / u16::from(FULL_WEIGHT);
+    let square = from.width as f32;
+    let center = |r: Rect| (r.left as f32 + square / 2.0, r.top as f32 + square / 2.0);
+    let (x0, y0) = center(from);
+    let (x1, y1) = center(to);
+    let (dx, dy) = (x1 - x0, y1 - y0);
+    let length = dx.hypot(dy);
+
+    let bounds = from.union(to);
+    let left = bounds.left.max(view_rect.left);
+    let top = bounds.top.max(view_rect.top);
+    let right = (bounds.left + bounds.width).min(view_rect.left + view_rect.width);
+    let bottom = (bounds.top + bounds.height).min(view_rect.top + view_rect.height);
+
+    for y in top..bottom {
+        for x in left..right {
+            let (px, py) = (x as f32 + 0.5 - x0, y as f32 + 0.5 - y0);
+            let inside = if length == 0.0 {
+                let r = px.hypot(py) / square;
+                (CIRCLE_RADIUS - CIRCLE_STROKE..=CIRCLE_RADIUS).contains(&r)
+            } else {
+                // Coordinates along and across the arrow.
+                let u = (px * dx + py * dy) / length / square;
+                let v = (px * dy - py * dx).abs() / length / square;
+                let head = length / square - head_length;
+                (0.0..=head).contains(&u) && v <= shaft_width / 2.0
+                    || (head..=head + head_length).contains(&u)
+                        && v <= (head + head_length - u) / head_length * head_width / 2.0
+            };
+            if inside && u16::from(BAYER[y % 4][x % 4]) < opacity {
+                view[(y - view_rect.top, x - view_rect.left)] = color;
+            }
+        }
+    }
+}
+
+/// Marks a legal destination within its square: a dot on an empty square,
+/// or a ring along the edge around a piece that can be captured.
+fn render_target(view: &mut ArrayViewMut2<u8>, capture: bool, color: u8) {
+    let square = view.ncols() as f32;
+    for ((y, x), c) in view.indexed_iter_mut() {
+        let r = (x as f32 + 0.5 - square / 2.0).hypot(y as f32 + 0.5 - square / 2.0) / square;
+        let inside = if capture {
+            (TARGET_RING_RADIUS - TARGET_RING_STROKE..=TARGET_RING_RADIUS).contains(&r)
+        } else {
+            r <= TARGET_DOT_RADIUS
+        };
+        if inside {
+            *c = color;
+        }
+    }
+}
+
+/// Squares between each checked king and the sliders that give check.
+fn check_ray(board: &Board, checked: Bitboard) -> Bitboard {
+    let mut ray = Bitboard::EMPTY;
+    for king in checked & board.kings() {
+        let Some(color) = board.color_at(king) else {
+            continue;
+        };
+        let sliders = board.by_color(!color) & (board.bishops() | board.rooks() | board.queens());
+        for checker in board.attacks_to(king, !color, board.occupied()) & sliders {
+            ray |= attacks::between(checker, king);
+        }
+    }
+    ray
+}
+
+/// Legal destinations of the piece on `selected`, if it belongs to the
+/// side to move. Castling marks the final square of the king.
+fn legal_targets(setup: &Setup, selected: Option<Square>) -> Bitboard {
+    let Some(selected) = selected else {
+        return Bitboard::EMPTY;
+    };
+    let Ok(pos) = Chess::from_setup(setup.clone(), CastlingMode::detect(setup))
+        .or_else(PositionError::ignore_invalid_castling_rights)
+        .or_else(PositionError::ignore_invalid_ep_square)
+        .or_else(PositionError::ignore_impossible_material)
+        .or_else(PositionError::ignore_impossible_check)
+    else {
+        return Bitboard::EMPTY;
+    };
+    pos.legal_moves()
+        .iter()
+        .filter(|m| m.from() == Some(selected))
+        .map(|m| match *m {
+            Move::Castle { king, rook } => {
+                CastlingSide::from_king_side(king < rook).king_to(pos.turn())
+            }
+            _ => m.to(),
+        })
+        .collect()
+}
+
+fn annotate_uci(uci: Option<&Uci>) -> Option<Annotation> {
+    match *uci? {
+        Uci::Normal { from, to, .. } => Some(Annotation {
+            from,
+            to,
+            brush: Brush::Green,
+            weight: FULL_WEIGHT,
+        }),
+        Uci::Put { to, .. } => Some(Annotation {
+            from: to,
+            to,
+            brush: Brush::Green,
+            weight: FULL_WEIGHT,
+        }),
+        _ => None,
+    }
+}
+
+/// Number of moves before the last one that stay highlighted, fading with
+/// age. A trail of more than one move overrides `previous_move`.
+fn trail_len(previous_move: bool, move_trail: u8) -> usize {
+    if move_trail > 1 {
+        usize::from(move_trail) - 1
+    } else {
+        usize::from(previous_move)
+    }
+}
+
+/// Sets the trail of `frame` from the highlights of earlier frames, and
+/// remembers its own highlight for later frames.
+fn follow_trail(frame: &mut RenderFrame, recent: &mut Vec<Bitboard>, len: usize) {
+    let mut shown = frame.highlighted;
+    frame.trail = recent
+        .iter()
+        .map(|&squares| {
+            let squares = squares & !shown;
+            shown |= squares;
+            squares
+        })
+        .collect();
+    recent.insert(0, frame.highlighted);
+    recent.truncate(len);
+}
+
+/// Formats a move for the move list, like `12. Nf3` or `12... e5`. Moves
+/// that are not legal in standard chess are shown in UCI notation.
+fn move_list_entry(before: &Fen, uci: &Uci) -> String {
+    let setup = &before.0;
+    let san = Chess::from_setup(setup.clone(), CastlingMode::detect(setup))
+        .ok()
+        .and_then(|pos| {
+            let m = uci.to_move(&pos).ok()?;
+            Some(SanPlus::from_move(pos, &m).to_string())
+        })
+        .unwrap_or_else(|| uci.to_string());
+    match setup.turn {
+        Color::White => format!("{}. {}", setup.fullmoves, san),
+        Color::Black => format!("{}... {}", setup.fullmoves, san),
+    }
+}
+
+/// Highlights the last move on the board after it was played. Castling
+/// highlights the final squares of king and rook instead. En passant also
+/// highlights the captured pawn, if the board `before` the move is known.
+fn highlight_uci(uci: Option<&Uci>, board: &Board, before: Option<&Board>) -> Bitboard {
+    match uci {
+        Some(&Uci::Normal { from, to, .. }) => match castled(from, to, board) {
+            Some((king, rook)) => Bitboard::from(king) | Bitboard::from(rook),
+            None => {
+                let victim = before.and_then(|before| en_passant_victim(from, to, before, board));
+                victim.map_or(Bitboard::EMPTY, Bitboard::from)
+                    | Bitboard::from(from)
+                    | Bitboard::from(to)
+            }
+        },
+        Some(&Uci::Put { to, .. }) => Bitboard::from(to),
+        _ => Bitboard::EMPTY,
+    }
+}
+
+/// Detects en passant by comparing the boards before and after a move, and
+/// returns the square of the captured pawn. The board after the move alone
+/// looks the same as after a regular pawn capture.
+fn en_passant_victim(from: Square, to: Square, before: &Board, after: &Board) -> Option<Square> {
+    let pawn = before
+        .piece_at(from)
+        .filter(|piece| piece.role == Role::Pawn)?;
+    let victim = Square::from_coords(to.file(), from.rank());
+    (from.file() != to.file()
+        && before.piece_at(to).is_none()
+        && after.piece_at(to) == Some(pawn)
+        && before.piece_at(victim) == Some((!pawn.color).pawn())
+        && after.piece_at(victim).is_none())
+    .then_some(victim)
+}
+
+/// Destination square and promoted piece of a move, given the side to
+/// move after it.
+fn promotion_uci(uci: Option<&Uci>, turn: Color) -> Option<(Square, Piece)> {
+    match *uci? {
+        Uci::Normal {
+            to,
+            promotion: Some(role),
+            ..
+        } => Some((to, role.of(!turn))),
+        _ => None,
+    }
+}
+
+/// Area of the promoted piece enlarged to twice the size of `square`,
+/// centered on it but kept within the `board`.
+fn promotion_rect(square: Rect, board: Rect) -> Rect {
+    let size = square.width * 2;
+    let center = |start: usize, min: usize, len: usize| {
+        (start + square.width / 2)
+            .saturating_sub(square.width)
+            .clamp(min, min + len.saturating_sub(size))
+    };
+    Rect {
+        left: center(square.left, board.left, board.width),
+        top: center(square.top, board.top, board.height),
+        width: size.min(board.width),
+        height: size.min(board.height),
+    }
+}
+
+/// Squares of a premove. Unlike `highlight_uci()`, castling is not
+/// detected, because the board does not show the move yet.
+fn premove_uci(uci: Option<&Uci>) -> Bitboard {
+    match uci {
+        Some(&Uci::Normal { from, to, .. }) => Bitboard::from(from) | Bitboard::from(to),
+        Some(&Uci::Put { to, .. }) => Bitboard::from(to),
+        _ => Bitboard::EMPTY,
+    }
+}
+
+/// Detects castling in both standard (`e1g1`) and king takes rook (`e1h1`)
+/// notation, and returns the final squares of king and rook.
+///
+/// A rook moving next to the king on the backrank can leave the same board
+/// as castling in king takes rook notation. That is treated as a rook move.
+fn castled(from: Square, to: Square, board: &Board) -> Option<(Square, Square)> {
+    let color = if from.rank() == Rank::First {
+        Color::White
+    } else if from.rank() == Rank::Eighth {
+        Color::Black
+    } else {
+        return None;
+    };
+    if to.rank() != from.rank() || to == from {
+        return None;
+    }
+    let side = CastlingSide::from_king_side(to > from);
+    let (king, rook) = (side.king_to(color), side.rook_to(color));
+    // Unlike a regular move, castling leaves the destination square empty
+    // or occupied by the king.
+    (board.piece_at(king) == Some(color.king())
+        && board.piece_at(rook) == Some(color.rook())
+        && board
+            .piece_at(to)
+            .filter(|&piece| piece != color.king())
+            .is_none())
+    .then_some((king, rook))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use gift::{block::Block, Decoder};
+
+    use super::*;
+    use crate::api::{BoardTheme, MetaValue};
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    fn themes() -> &'static Themes {
+        static THEMES: OnceLock<Themes> = OnceLock::new();
+        THEMES.get_or_init(Themes::new)
+    }
+
+    fn theme() -> &'static Theme {
+        themes().get(None, BoardTheme::default())
+    }
+
+    fn frame(fen: &str) -> RequestFrame {
+        RequestFrame {
+            fen: fen.parse().expect("valid fen"),
+            ..RequestFrame::default()
+        }
+    }
+
+    fn encode(render: Render) -> Vec<u8> {
+        render.infallible().flat_map(|part| part.to_vec()).collect()
+    }
+
+    fn blocks(gif: &[u8]) -> Vec<Block> {
+        Decoder::new(gif)
+            .into_blocks()
+            .collect::<Result<_, _>>()
+            .expect("valid gif")
+    }
+
+    fn images(blocks: &[Block]) -> Vec<block::ImageDesc> {
+        blocks
+            .iter()
+            .filter_map(|block| match block {
+                Block::ImageDesc(desc) => Some(*desc),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn delays(blocks: &[Block]) -> Vec<u16> {
+        blocks
+            .iter()
+            .filter_map(|block| match block {
+                Block::GraphicControl(ctrl) => Some(ctrl.delay_time_cs()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn identical_frames_are_merged() {
+        let params = RequestBody::builder()
+            .frames([
+                RequestFrame {
+                    delay: Some(100),
+                    ..frame(START)
+                },
+                RequestFrame {
+                    delay: Some(50),
+                    ..frame(START)
+                },
+            ])
+            .end_frame(false)
+            .build()
+            .unwrap();
+        let blocks = blocks(&encode(Render::new_animation(theme(), params, Format::Gif)));
+        assert_eq!(images(&blocks).len(), 1);
+        assert_eq!(delays(&blocks), [150]);
+    }
+
+    fn loop_counts(blocks: &[Block]) -> Vec<u16> {
+        blocks
+            .iter()
+            .filter_map(|block| match block {
+                Block::Application(app) => app.loop_count(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loop_extension_repeats_requested_loops() {
+        for (loops, expected) in [(None, &[0][..]), (Some(1), &[]), (Some(3), &[2])] {
+            let mut builder = RequestBody::builder().frame(frame(START));
+            if let Some(loops) = loops {
+                builder = builder.loops(NonZeroU16::new(loops).unwrap());
+            }
+            let params = builder.build().unwrap();
+            let blocks = blocks(&encode(Render::new_animation(theme(), params, Format::Gif)));
+            assert_eq!(loop_counts(&blocks), expected, "loops {:?}", loops);
+        }
+    }
+
+    fn image_data(blocks: &[Block]) -> Vec<&[u8]> {
+        blocks
+            .iter()
+            .filter_map(|block| match block {
+                Block::ImageData(data) => Some(data.data()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn still(params: RequestParams) -> Vec<u8> {
+        encode(Render::new_image(theme(), params, Format::Gif))
+    }
+
+    #[test]
+    fn blindfold_draws_no_pieces() {
+        let blindfold = still(RequestParams {
+            fen: START.parse().unwrap(),
+            last_move: Some("e2e4".parse().unwrap()),
+            check: CheckSquare::Yes,
+            blindfold: true,
+            ..RequestParams::default()
+        });
+        let empty = still(RequestParams {
+            fen: "8/8/8/8/8/8/8/8 w - - 0 1".parse().unwrap(),
+            last_move: Some("e2e4".parse().unwrap()),
+            ..RequestParams::default()
+        });
+        let sighted = still(RequestParams {
+            fen: START.parse().unwrap(),
+            last_move: Some("e2e4".parse().unwrap()),
+            ..RequestParams::default()
+        });
+        assert_eq!(image_data(&blocks(&blindfold)), image_data(&blocks(&empty)));
+        assert_ne!(
+            image_data(&blocks(&blindfold)),
+            image_data(&blocks(&sighted))
+        );
+    }
+
+    fn highlighted(uci: &str, board_fen: &str) -> Bitboard {
+        let board: Board = board_fen.parse().unwrap();
+        highlight_uci(Some(&uci.parse().unwrap()), &board, None)
+    }
+
+    #[test]
+    fn castling_highlights_king_and_rook() {
+        let castled = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQ1RK1";
+        let expected = Bitboard::from(Square::G1) | Bitboard::from(Square::F1);
+        assert_eq!(highlighted("e1g1", castled), expected);
+        assert_eq!(highlighted("e1h1", castled), expected);
+        assert_eq!(
+            highlighted("e8c8", "2kr1bnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR"),
+            Bitboard::from(Square::C8) | Bitboard::from(Square::D8)
+        );
+    }
+
+    #[test]
+    fn chess960_castling_highlights_king_and_rook() {
+        // King on b1 takes the rook on a1 to castle queenside.
+        assert_eq!(
+            highlighted("b1a1", "1k6/8/8/8/8/8/8/2KR4"),
+            Bitboard::from(Square::C1) | Bitboard::from(Square::D1)
+        );
+        // King on f1 takes the rook on g1 to castle kingside.
+        assert_eq!(
+            highlighted("f1g1", "1k6/8/8/8/8/8/8/5RK1"),
+            Bitboard::from(Square::G1) | Bitboard::from(Square::F1)
+        );
+        // A rook move that is not castling.
+        assert_eq!(
+            highlighted("a1d1", "1k6/8/8/8/8/8/8/3R2K1"),
+            Bitboard::from(Square::A1) | Bitboard::from(Square::D1)
+        );
+    }
+
+    fn screen(blocks: &[Block]) -> block::LogicalScreenDesc {
+        blocks
+            .iter()
+            .find_map(|block| match block {
+                Block::LogicalScreenDesc(desc) => Some(*desc),
+                _ => None,
+            })
+            .expect("logical screen descriptor")
+    }
+
+    fn comments(blocks: &[Block]) -> Vec<String> {
+        blocks
+            .iter()
+            .filter_map(|block| match block {
+                Block::Comment(comment) => {
+                    Some(String::from_utf8(comment.comments().concat()).unwrap())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn json_body_end_to_end() {
+        let body: RequestBody = serde_json::from_str(
+            r#"{
+                "white": "GM DrDrunkenstein",
+                "black": "GM Zhigalko_Sergei",
+                "whiteRating": 2888,
+                "blackRating": 2895,
+                "comment": "https://lichess.org/Q0iQs5Zi",
+                "orientation": "black",
+                "theme": "wood",
+                "delay": 50,
+                "frames": [
+                    { "fen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1" },
+                    {
+                        "fen": "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1",
+                        "lastMove": "e2e4"
+                    },
+                    {
+                        "fen": "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2",
+                        "lastMove": "e7e5",
+                        "delay": 200
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let render = Render::try_from_request(themes(), body, Format::Gif).unwrap();
+        let blocks = blocks(&encode(render));
+        let screen = screen(&blocks);
+        assert_eq!((screen.screen_width(), screen.screen_height()), (720, 840));
+        assert_eq!(comments(&blocks), ["https://lichess.org/Q0iQs5Zi"]);
+        // Three frames and the black end frame.
+        assert_eq!(images(&blocks).len(), 4);
+        assert_eq!(delays(&blocks), [50, 50, 200, 1]);
+    }
+
+    #[test]
+    fn pgn_is_validated() {
+        let body = || RequestBody {
+            delay: 50,
+            end_frame: Some(false),
+            ..RequestBody::default()
+        };
+        let render = Render::from_pgn(themes(), "1. e4 e5 2. Nf3", body(), Format::Gif).unwrap();
+        assert_eq!(images(&blocks(&encode(render))).len(), 4);
+
+        let render = Render::from_pgn(
+            themes(),
+            "1. e4 e5",
+            RequestBody { delay: 1, ..body() },
+            Format::Gif,
+        );
+        assert!(matches!(
+            render,
+            Err(PgnError::Invalid(ApiError::BadDelay(1)))
+        ));
+
+        let render = Render::from_pgn(themes(), "1. e4 e5 2. Ke3", body(), Format::Gif);
+        assert!(matches!(render, Err(PgnError::IllegalMove { ply: 3, .. })));
+    }
+
+    fn color_tables(blocks: &[Block]) -> Vec<&[u8]> {
+        blocks
+            .iter()
+            .filter_map(|block| match block {
+                Block::GlobalColorTable(table) => Some(table.colors()),
+                Block::LocalColorTable(table) => Some(table.colors()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn is_gray(colors: &[u8]) -> bool {
+        colors
+            .chunks(3)
+            .all(|rgb| rgb[0] == rgb[1] && rgb[1] == rgb[2])
+    }
+
+    #[test]
+    fn grayscale_color_table() {
+        let params = |grayscale| RequestParams {
+            last_move: Some("e2e4".parse().unwrap()),
+            arrows: vec!["g1f3".parse().unwrap()],
+            highlight: Some("#9bc700".parse().unwrap()),
+            background: Some("#204080".parse().unwrap()),
+            grayscale,
+            ..RequestParams::default()
+        };
+        let gray = blocks(&still(params(true)));
+        assert!(!color_tables(&gray).is_empty());
+        assert!(color_tables(&gray).into_iter().all(is_gray));
+        let color = blocks(&still(params(false)));
+        assert!(!color_tables(&color).into_iter().all(is_gray));
+
+        let body = RequestBody::builder()
+            .frames([
+                frame(START),
+                RequestFrame {
+                    last_move: Some("e2e4".parse().unwrap()),
+                    ..frame("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1")
+                },
+            ])
+            .previous_move(true)
+            .grayscale(true)
+            .build()
+            .unwrap();
+        let wood = themes().get(None, BoardTheme::Wood);
+        let gray = blocks(&encode(Render::new_animation(wood, body, Format::Gif)));
+        assert!(color_tables(&gray).into_iter().all(is_gray));
+    }
+
+    #[test]
+    fn bars_follow_orientation() {
+        let bars = PlayerBars::from(
+            Player {
+                name: Some(PlayerName::from("white").unwrap()),
+                rating: None,
+            },
+            Player {
+                name: Some(PlayerName::from("black").unwrap()),
+                rating: None,
+            },
+            false,
+            false,
+            false,
+        )
+        .unwrap();
+        for (orientation, top, bottom) in [
+            (Orientation::White, Color::Black, Color::White),
+            (Orientation::Black, Color::White, Color::Black),
+        ] {
+            let (player, color) = bars.top(orientation);
+            assert_eq!(color, top);
+            assert_eq!(player.name.as_deref(), Some(top.fold_wb("white", "black")));
+            let (player, color) = bars.bottom(orientation);
+            assert_eq!(color, bottom);
+            assert_eq!(
+                player.name.as_deref(),
+                Some(bottom.fold_wb("white", "black"))
+            );
+        }
+
+        // Only white has a name, so only the bar of white has text.
+        let bar_height = theme().bar_height();
+        for (orientation, white_on_top) in [(Orientation::White, false), (Orientation::Black, true)]
+        {
+            let gif = still(RequestParams {
+                white: Some(PlayerName::from("Magnus").unwrap()),
+                orientation,
+                ..RequestParams::default()
+            });
+            let blocks = blocks(&gif);
+            let width = usize::from(screen(&blocks).screen_width());
+            let pixels = image_data(&blocks)[0];
+            let (top, bottom) = (
+                &pixels[..(bar_height * width)],
+                &pixels[(pixels.len() - bar_height * width)..],
+            );
+            let background = theme().bar_color();
+            let plain = |bar: &[u8]| bar.iter().all(|&c| c == background);
+            assert_eq!(plain(top), !white_on_top);
+            assert_eq!(plain(bottom), white_on_top);
+        }
+    }
+
+    /// Positions of knights moving back and forth, so that every frame
+    /// changes the board.
+    const SHUFFLE: [&str; 4] = [
+        START,
+        "rnbqkbnr/pppppppp/8/8/8/5N2/PPPPPPPP/RNBQKB1R b KQkq - 1 1",
+        "rnbqkb1r/pppppppp/5n2/8/8/5N2/PPPPPPPP/RNBQKB1R w KQkq - 2 2",
+        "rnbqkb1r/pppppppp/5n2/8/8/8/PPPPPPPP/RNBQKBNR b KQkq - 3 2",
+    ];
+
+    /// Delays of the `fcTL` chunks of an APNG, in milliseconds.
+    fn apng_delays_ms(png: &[u8]) -> Vec<u32> {
+        let mut delays = Vec::new();
+        let mut rest = &png[8..];
+        while rest.len() >= 12 {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..(8 + len)]);
+            if kind == b"fcTL" {
+                let num = u32::from(u16::from_be_bytes([data[20], data[21]]));
+                let den = u32::from(u16::from_be_bytes([data[22], data[23]]));
+                delays.push(num * 1000 / den);
+            }
+            rest = &rest[(12 + len)..];
+        }
+        delays
+    }
+
+    #[test]
+    fn millisecond_delays_keep_total_duration() {
+        let body = || {
+            RequestBody::builder()
+                .frames((0..6).map(|i| frame(SHUFFLE[i % 4])))
+                .delay_ms(333)
+                .end_frame(false)
+                .build()
+                .unwrap()
+        };
+
+        let gif = blocks(&encode(Render::new_animation(theme(), body(), Format::Gif)));
+        let delays = delays(&gif);
+        assert_eq!(delays.len(), 6);
+        assert!(delays.iter().all(|&cs| cs == 33 || cs == 34));
+        assert_eq!(delays.iter().map(|&cs| u32::from(cs)).sum::<u32>(), 200);
+
+        let apng = encode(Render::new_animation(theme(), body(), Format::Apng));
+        assert_eq!(apng_delays_ms(&apng), [333; 6]);
+    }
+
+    #[test]
+    fn dimensions_match_screen() {
+        let small = themes().get(Some(40), BoardTheme::default());
+        let renders = [
+            (
+                Render::new_image(theme(), RequestParams::default(), Format::Gif),
+                Some((720, 720)),
+            ),
+            (
+                Render::new_image(
+                    theme(),
+                    RequestParams {
+                        white: Some(PlayerName::from("white").unwrap()),
+                        ..RequestParams::default()
+                    },
+                    Format::Gif,
+                ),
+                Some((720, 840)),
+            ),
+            (
+                Render::new_image(small, RequestParams::default(), Format::Gif),
+                Some((320, 320)),
+            ),
+            (
+                Render::new_image(
+                    theme(),
+                    RequestParams {
+                        coordinate_margin: true,
+                        second_fen: Some(Fen::default()),
+                        ..RequestParams::default()
+                    },
+                    Format::Gif,
+                ),
+                None,
+            ),
+            (
+                Render::new_animation(
+                    theme(),
+                    RequestBody::builder()
+                        .frame(RequestFrame {
+                            clock: Some(Clock {
+                                white: 6000,
+                                black: 6000,
+                            }),
+                            eval: Some(Eval::Cp(20)),
+                            ..frame(START)
+                        })
+                        .move_list(true)
+                        .build()
+                        .unwrap(),
+                    Format::Gif,
+                ),
+                None,
+            ),
+        ];
+        for (render, expected) in renders {
+            let dimensions = render.dimensions();
+            if let Some(expected) = expected {
+                assert_eq!(dimensions, expected);
+            }
+            let screen = screen(&blocks(&encode(render)));
+            assert_eq!(dimensions, (screen.screen_width(), screen.screen_height()));
+        }
+    }
+
+    #[test]
+    fn long_comments_are_split_into_sub_blocks() {
+        let text = "0123456789".repeat(60);
+        let comment = gif_comment(&text);
+        assert_eq!(comment.comments().len(), 3);
+        assert!(comment.comments().iter().all(|chunk| chunk.len() <= 255));
+        assert_eq!(comment.comments().concat(), text.as_bytes());
+
+        let name = PlayerName::from(&"x".repeat(100)).unwrap();
+        let body = RequestBody::builder()
+            .frame(frame(START))
+            .comment(Comment::from(&"c".repeat(255)).unwrap())
+            .metadata(GameMeta {
+                white: Some(name),
+                black: Some(name),
+                event: Some(MetaValue::from(&"e".repeat(100)).unwrap()),
+                ..GameMeta::default()
+            })
+            .build()
+            .unwrap();
+        let blocks = blocks(&encode(Render::new_animation(theme(), body, Format::Gif)));
+        let comments = comments(&blocks);
+        assert_eq!(comments.len(), 2);
+        assert_eq!(comments[0], "c".repeat(255));
+        assert_eq!(
+            comments[1],
+            format!(
+                "white={}\nblack={}\nevent={}\n",
+                name,
+                name,
+                "e".repeat(100)
+            )
+        );
+        assert!(comments[1].len() > 255);
+    }
+
+    #[test]
+    fn animation_without_frames() {
+        assert!(matches!(
+            RequestBody::default().validate(),
+            Err(ApiError::NoFrames)
+        ));
+        assert!(matches!(
+            Render::try_from_request(themes(), RequestBody::default(), Format::Gif),
+            Err(ApiError::NoFrames)
+        ));
+
+        // Rendered anyway, it is a single frame of the starting position.
+        let render = Render::new_animation(theme(), RequestBody::default(), Format::Gif);
+        assert_eq!(render.frame_count(), Some(2));
+        let animation = blocks(&encode(render));
+        assert_eq!(images(&animation).len(), 2);
+        let still = blocks(&still(RequestParams::default()));
+        assert_eq!(image_data(&animation)[0], image_data(&still)[0]);
+    }
+
+    #[test]
+    fn interlaced_rows_follow_passes() {
+        assert_eq!(
+            interlaced_rows(10).collect::<Vec<_>>(),
+            [0, 8, 4, 2, 6, 1, 3, 5, 7, 9]
+        );
+        let mut rows = interlaced_rows(720).collect::<Vec<_>>();
+        rows.sort_unstable();
+        assert!(rows.into_iter().eq(0..720));
+    }
+
+    #[test]
+    fn interlaced_still_image() {
+        let params = |interlace| RequestParams {
+            fen: START.parse().unwrap(),
+            last_move: Some("e2e4".parse().unwrap()),
+            interlace,
+            ..RequestParams::default()
+        };
+        let interlaced = blocks(&still(params(true)));
+        let plain = blocks(&still(params(false)));
+        assert!(images(&interlaced)[0].interlaced());
+        assert!(!images(&plain)[0].interlaced());
+
+        let width = usize::from(images(&plain)[0].width());
+        let (interlaced, plain) = (image_data(&interlaced)[0], image_data(&plain)[0]);
+        assert_eq!(interlaced.len(), plain.len());
+        let mut deinterlaced = vec![0; plain.len()];
+        for (row, y) in interlaced
+            .chunks(width)
+            .zip(interlaced_rows(plain.len() / width))
+        {
+            deinterlaced[(y * width)..((y + 1) * width)].copy_from_slice(row);
+        }
+        assert_eq!(deinterlaced, plain);
+    }
+
+    #[test]
+    fn only_first_frame_is_interlaced() {
+        let body = RequestBody::builder()
+            .frames(SHUFFLE.iter().map(|fen| frame(fen)))
+            .interlace(true)
+            .build()
+            .unwrap();
+        let blocks = blocks(&encode(Render::new_animation(theme(), body, Format::Gif)));
+        let interlaced = images(&blocks)
+            .iter()
+            .map(|desc| desc.interlaced())
+            .collect::<Vec<_>>();
+        assert!(interlaced.len() > 1);
+        assert!(interlaced[0]);
+        assert!(interlaced[1..].iter().all(|&interlaced| !interlaced));
+    }
+
+    #[test]
+    fn final_delay_holds_last_position() {
+        let body = |final_delay: Option<u16>, end_frame| {
+            let mut builder = RequestBody::builder()
+                .frames(SHUFFLE[..3].iter().map(|fen| frame(fen)))
+                .delay(50)
+                .end_frame(end_frame);
+            if let Some(final_delay) = final_delay {
+                builder = builder.final_delay(final_delay);
+            }
+            builder.build().unwrap()
+        };
+        let delays = |body| {
+            delays(&blocks(&encode(Render::new_animation(
+                theme(),
+                body,
+                Format::Gif,
+            ))))
+        };
+        assert_eq!(delays(body(None, true)), [50, 50, 50, 1]);
+        // The kork frame keeps its delay.
+        assert_eq!(delays(body(Some(300), true)), [50, 50, 300, 1]);
+        assert_eq!(delays(body(Some(300), false)), [50, 50, 300]);
+    }
+
+    #[test]
+    fn en_passant_highlights_captured_pawn() {
+        let uci = "e5d6".parse().unwrap();
+        let before: Board = "4k3/8/8/3pP3/8/8/8/4K3".parse().unwrap();
+        let after: Board = "4k3/8/3P4/8/8/8/8/4K3".parse().unwrap();
+        let squares = Bitboard::from(Square::E5) | Bitboard::from(Square::D6);
+        assert_eq!(
+            highlight_uci(Some(&uci), &after, Some(&before)),
+            squares | Bitboard::from(Square::D5)
+        );
+        // Without the board before the move, it looks like a regular capture.
+        assert_eq!(highlight_uci(Some(&uci), &after, None), squares);
+
+        let capture: Board = "4k3/8/3p4/4P3/8/8/8/4K3".parse().unwrap();
+        assert_eq!(highlight_uci(Some(&uci), &after, Some(&capture)), squares);
+
+        let black: Board = "4k3/8/8/8/3Pp3/8/8/4K3".parse().unwrap();
+        assert_eq!(
+            highlight_uci(
+                Some(&"e4d3".parse().unwrap()),
+                &"4k3/8/8/8/8/3p4/8/4K3".parse().unwrap(),
+                Some(&black)
+            ),
+            Bitboard::from(Square::E4) | Bitboard::from(Square::D3) | Bitboard::from(Square::D4)
+        );
+    }
+
+    /// The bundled sprite sheet, re-encoded with a local color table
+    /// instead of a global one.
+    fn local_table_theme() -> &'static Theme {
+        static THEME: OnceLock<Theme> = OnceLock::new();
+        THEME.get_or_init(|| {
+            let mut gif = Vec::new();
+            let mut encoder = gift::Encoder::new(&mut gif).into_block_enc();
+            let mut config = block::ColorTableConfig::default();
+            let mut colors = Vec::new();
+            for block in blocks(include_bytes!("../theme/sprite.gif")) {
+                match block {
+                    Block::LogicalScreenDesc(desc) => {
+                        config = desc.color_table_config();
+                        encoder
+                            .encode(
+                                desc.with_color_table_config(block::ColorTableConfig::default()),
+                            )
+                            .unwrap();
+                    }
+                    Block::GlobalColorTable(table) => colors = table.colors().to_vec(),
+                    Block::ImageDesc(desc) => {
+                        encoder
+                            .encode(desc.with_color_table_config(config))
+                            .unwrap();
+                        encoder
+                            .encode(block::LocalColorTable::with_colors(&colors))
+                            .unwrap();
+                    }
+                    block => encoder.encode(block).unwrap(),
+                }
+            }
+            Theme::try_new(&gif).expect("local color table sprite")
+        })
+    }
+
+    #[test]
+    fn local_color_table_theme() {
+        let theme = local_table_theme();
+        assert!(theme
+            .palette(None, false, None, 0, false, false)
+            .local_color_table()
+            .is_some());
+
+        let still = encode(Render::new_image(
+            theme,
+            RequestParams {
+                last_move: Some("e2e4".parse().unwrap()),
+                ..RequestParams::default()
+            },
+            Format::Gif,
+        ));
+        let body = RequestBody::builder()
+            .frames(SHUFFLE.iter().map(|fen| frame(fen)))
+            .build()
+            .unwrap();
+        let animation = encode(Render::new_animation(theme, body, Format::Gif));
+        for gif in [still, animation] {
+            let blocks = blocks(&gif);
+            assert_eq!(
+                screen(&blocks).color_table_config().existence(),
+                block::ColorTableExistence::Absent
+            );
+            assert!(!blocks
+                .iter()
+                .any(|block| matches!(block, Block::GlobalColorTable(_))));
+            let images = images(&blocks);
+            assert!(!images.is_empty());
+            assert!(images
+                .iter()
+                .all(|desc| desc.color_table_config().existence()
+                    == block::ColorTableExistence::Present));
+            // Every image descriptor is directly followed by its table.
+            let tables = blocks
+                .windows(2)
+                .filter(|pair| matches!(pair, [Block::ImageDesc(_), Block::LocalColorTable(_)]))
+                .count();
+            assert_eq!(tables, images.len());
+        }
+    }
+
+    #[test]
+    fn still_image_structure() {
+        let blocks = blocks(&still(RequestParams::default()));
+        let screen = screen(&blocks);
+        assert_eq!((screen.screen_width(), screen.screen_height()), (720, 720));
+        assert_eq!(loop_counts(&blocks), [0]);
+        assert_eq!(
+            comments(&blocks),
+            ["https://github.com/lichess-org/lila-gif"]
+        );
+        assert_eq!(images(&blocks).len(), 1);
+    }
+
+    fn e2e4(mirror: bool) -> RequestBody {
+        RequestBody::builder()
+            .frames([
+                frame(START),
+                RequestFrame {
+                    last_move: Some("e2e4".parse().unwrap()),
+                    ..frame("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1")
+                },
+            ])
+            .mirror(mirror)
+            .end_frame(false)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn diff_frame_covers_move() {
+        let body = e2e4(false);
+        let blocks = blocks(&encode(Render::new_animation(theme(), body, Format::Gif)));
+        let images = images(&blocks);
+        assert_eq!(images.len(), 2);
+        let diff = images[1];
+        assert_eq!(
+            (diff.left(), diff.top(), diff.width(), diff.height()),
+            (4 * 90, 4 * 90, 90, 3 * 90)
+        );
+        let disposals = blocks
+            .iter()
+            .filter_map(|block| match block {
+                Block::GraphicControl(ctrl) => Some(ctrl.disposal_method()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(disposals.len(), 2);
+        assert_eq!(disposals[1], block::DisposalMethod::Keep);
+    }
+
+    #[test]
+    fn mirror_reverses_files() {
+        for (orientation, a1) in [(Orientation::White, 0), (Orientation::Black, 7)] {
+            assert_eq!(screen_x(orientation, false, Square::A1), a1);
+            assert_eq!(screen_x(orientation, true, Square::A1), 7 - a1);
+            assert_eq!(
+                screen_x(orientation, true, Square::E4),
+                7 - screen_x(orientation, false, Square::E4)
+            );
+        }
+
+        // The e-file moves to the fourth column.
+        let blocks = blocks(&encode(Render::new_animation(
+            theme(),
+            e2e4(true),
+            Format::Gif,
+        )));
+        let diff = images(&blocks)[1];
+        assert_eq!(
+            (diff.left(), diff.top(), diff.width(), diff.height()),
+            (3 * 90, 4 * 90, 90, 3 * 90)
+        );
+    }
+
+    #[test]
+    fn live_render_continues_stream() {
+        let moves = [
+            ("4k3/1P6/8/8/8/8/8/4K3 w - - 0 1", None),
+            ("4k3/1P6/8/8/8/8/8/3K4 b - - 1 1", Some("e1d1")),
+            ("3k4/1P6/8/8/8/8/8/3K4 w - - 2 2", Some("e8d8")),
+            ("1Q1k4/8/8/8/8/8/8/3K4 b - - 0 2", Some("b7b8q")),
+            ("1Q6/3k4/8/8/8/8/8/3K4 w - - 1 3", Some("d8d7")),
+            ("8/3k4/8/8/8/8/8/1Q1K4 b - - 2 3", Some("b8b1")),
+        ];
+        let frames = |range: std::ops::Range<usize>| {
+            moves[range].iter().map(|&(fen, uci)| RequestFrame {
+                last_move: uci.map(|uci| uci.parse().unwrap()),
+                ..frame(fen)
+            })
+        };
+        let params = || {
+            RequestBody::builder()
+                .frame(frame(START))
+                .move_trail(3)
+                .promotion_overlay(true)
+                .delay_ms(333)
+                .poster(START.parse().unwrap())
+                .end_frame(false)
+                .build()
+                .unwrap()
+        };
+        let stream = |len| {
+            let frames: Vec<_> = frames(0..len).collect();
+            encode(Render::from_frame_iter(
+                theme(),
+                params(),
+                frames,
+                Format::Gif,
+            ))
+        };
+
+        let full = stream(moves.len());
+        // The poster, every move, and the promotion overlay.
+        assert_eq!(images(&blocks(&full)).len(), moves.len() + 2);
+        for shown in 1..moves.len() {
+            let mut live = stream(shown);
+            assert_eq!(live.pop(), Some(0x3b), "trailer");
+            let mut render = LiveRender::new(theme(), params(), frames(0..shown));
+            for next in frames(shown..moves.len()) {
+                live.extend_from_slice(&render.render_incremental(next).unwrap());
+            }
+            live.push(0x3b);
+            assert!(live == full, "{} frames shown", shown);
+        }
+    }
+
+    #[test]
+    fn comments_split_on_char_boundaries() {
+        for text in [
+            "é".repeat(200),
+            "♞🐴".repeat(60),
+            format!("x{}", "🐴".repeat(70)),
+        ] {
+            let comment = gif_comment(&text);
+            assert!(comment.comments().len() > 1);
+            for chunk in comment.comments() {
+                assert!(chunk.len() <= 255);
+                assert!(std::str::from_utf8(chunk).is_ok());
+            }
+            assert_eq!(comment.comments().concat(), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn ascii_comments_replace_other_characters() {
+        let body = |ascii_comments| {
+            RequestBody::builder()
+                .frame(frame(START))
+                .white(PlayerName::from("Ding Liren 丁立人").unwrap())
+                .comment(Comment::from("Échecs 🐴").unwrap())
+                .metadata(GameMeta {
+                    white: Some(PlayerName::from("José Raúl Capablanca").unwrap()),
+                    ..GameMeta::default()
+                })
+                .ascii_comments(ascii_comments)
+                .build()
+                .unwrap()
+        };
+        let comments = |ascii_comments| {
+            comments(&blocks(&encode(Render::new_animation(
+                theme(),
+                body(ascii_comments),
+                Format::Gif,
+            ))))
+        };
+        assert_eq!(
+            comments(false),
+            ["Échecs 🐴", "white=José Raúl Capablanca\n"]
+        );
+        assert_eq!(comments(true), ["?checs ?", "white=Jos? Ra?l Capablanca\n"]);
+    }
+
+    #[test]
+    fn frames_without_delay_get_shortest_delay() {
+        let body = RequestBody::builder()
+            .frames(SHUFFLE[..2].iter().map(|fen| frame(fen)))
+            .end_frame(false)
+            .build()
+            .unwrap();
+        let blocks = blocks(&encode(Render::new_animation(theme(), body, Format::Gif)));
+        assert_eq!(delays(&blocks), [2, 2]);
+    }
+
+    #[test]
+    fn embedded_fen_round_trips() {
+        for fen in [
+            START,
+            "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3",
+            "8/8/8/4k3/8/8/4K3/8 b - - 42 90",
+        ] {
+            let params = |embed_fen| RequestParams {
+                fen: fen.parse().unwrap(),
+                embed_fen,
+                ..RequestParams::default()
+            };
+            let with_fen = comments(&blocks(&still(params(true))));
+            let embedded = with_fen
+                .iter()
+                .flat_map(|comment| comment.lines())
+                .find_map(|line| line.strip_prefix("fen="))
+                .expect("fen line");
+            let recovered: Fen = embedded.parse().unwrap();
+            assert_eq!(recovered.to_string(), fen);
+
+            let without = comments(&blocks(&still(params(false))));
+            assert!(without.iter().all(|comment| !comment.contains("fen=")));
+        }
+    }
+
+    /// Data of the first PNG chunk of the given kind.
+    fn png_chunk<'a>(png: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+        let mut rest = &png[8..];
+        while rest.len() >= 12 {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            if &rest[4..8] == kind {
+                return Some(&rest[8..(8 + len)]);
+            }
+            rest = &rest[(12 + len)..];
+        }
+        None
+    }
+
+    #[test]
+    fn density_doubles_pixels() {
+        let params = |density| RequestParams {
+            fen: START.parse().unwrap(),
+            last_move: Some("e2e4".parse().unwrap()),
+            size: Some(40),
+            density,
+            ..RequestParams::default()
+        };
+        let render =
+            |density, format| Render::try_from_params(themes(), params(density), format).unwrap();
+        assert_eq!(render(None, Format::Gif).dimensions(), (320, 320));
+        assert_eq!(render(Some(2), Format::Gif).dimensions(), (640, 640));
+
+        let single = blocks(&encode(render(None, Format::Gif)));
+        let double = blocks(&encode(render(Some(2), Format::Gif)));
+        let screen = screen(&double);
+        assert_eq!((screen.screen_width(), screen.screen_height()), (640, 640));
+        // Every pixel is repeated in both directions.
+        let (single, double) = (image_data(&single)[0], image_data(&double)[0]);
+        for (y, row) in double.chunks(640).enumerate() {
+            for (x, &pixel) in row.iter().enumerate() {
+                assert_eq!(pixel, single[(y / 2) * 320 + x / 2]);
+            }
+        }
+
+        let png = encode(render(Some(2), Format::Png));
+        let phys = png_chunk(&png, b"pHYs").expect("pHYs chunk");
+        assert_eq!(phys, [0, 0, 0x16, 0x26, 0, 0, 0x16, 0x26, 1]);
+        let png = encode(render(None, Format::Png));
+        assert!(png_chunk(&png, b"pHYs").is_none());
+    }
+
+    #[test]
+    fn poster_is_shown_briefly() {
+        let poster = "8/8/4k3/8/8/8/2R5/2K5 w - - 0 60";
+        let body = RequestBody::builder()
+            .frames(SHUFFLE[..2].iter().map(|fen| frame(fen)))
+            .delay(50)
+            .poster(poster.parse().unwrap())
+            .build()
+            .unwrap();
+        let gif = encode(Render::new_animation(theme(), body, Format::Gif));
+        assert_eq!(delays(&blocks(&gif)), [MIN_DELAY, 50, 50, 1]);
+
+        let first = Decoder::new(&gif[..])
+            .into_frames()
+            .next()
+            .unwrap()
+            .unwrap();
+        let ctrl = first.graphic_control_ext.expect("graphic control");
+        assert_eq!(ctrl.delay_time_cs(), MIN_DELAY);
+        // Steps of gift do not carry delays, but composite the frames.
+        let first = Decoder::new(&gif[..]).into_steps().next().unwrap().unwrap();
+        let still = still(RequestParams {
+            fen: poster.parse().unwrap(),
+            ..RequestParams::default()
+        });
+        let still = Decoder::new(&still[..])
+            .into_steps()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert!(first.raster().as_u8_slice() == still.raster().as_u8_slice());
+    }
+
+    #[test]
+    fn cached_params_are_validated() {
+        static CACHE: OnceLock<ImageCache> = OnceLock::new();
+        let cache = CACHE.get_or_init(|| ImageCache::new(4));
+        let params = |density| RequestParams {
+            last_move: Some("e2e4".parse().unwrap()),
+            density,
+            ..RequestParams::default()
+        };
+        assert!(matches!(
+            Render::try_from_cached_params(cache, themes(), params(Some(0)), Format::Gif),
+            Err(ApiError::BadDensity(0))
+        ));
+
+        let uncached =
+            encode(Render::try_from_params(themes(), params(None), Format::Gif).unwrap());
+        // Rendered and added to the cache, then served from it.
+        for _ in 0..2 {
+            let render =
+                Render::try_from_cached_params(cache, themes(), params(None), Format::Gif).unwrap();
+            assert_eq!(encode(render), uncached);
+        }
     }
 }
@@ -0,0 +1,30 @@
+//! Asynchronous adapter for [`Render`], for serving images without
+//! blocking the runtime.
+
+use bytes::Bytes;
+use futures::{stream, Stream};
+use tokio::{sync::mpsc, task};
+
+use crate::render::{Render, RenderError};
+
+/// Parts rendered ahead of the consumer, so that a slow client does not
+/// let the render run far ahead.
+const BUFFERED_PARTS: usize = 4;
+
+/// Renders on the blocking thread pool of the current Tokio runtime and
+/// yields the parts as they are produced. Rendering stops early once the
+/// stream is dropped.
+pub fn render_stream(render: Render) -> impl Stream<Item = Result<Bytes, RenderError>> + Send {
+    let (tx, rx) = mpsc::channel(BUFFERED_PARTS);
+    task::spawn_blocking(move || {
+        for part in render {
+            if tx.blocking_send(part).is_err() {
+                break;
+            }
+        }
+    });
+    stream::unfold(rx, |mut rx| async move {
+        let part = rx.recv().await?;
+        Some((part, rx))
+    })
+}
@@ -1,20 +1,52 @@
-use gift::block::{ColorTableConfig, GlobalColorTable};
-use ndarray::{s, Array2, ArrayView2};
+#[cfg(feature = "image")]
+use std::cmp::Reverse;
+use std::{collections::HashMap, error, fmt, iter, sync::Mutex};
+
+use gift::block::{
+    ColorTableConfig, ColorTableExistence, ColorTableOrdering, GlobalColorTable, LocalColorTable,
+};
+use ndarray::{concatenate, s, Array2, ArrayView2, Axis, Zip};
+use rusttype::Font;
 use shakmaty::{Piece, Role};
 
+use crate::api::{BarStyle, BoardTheme, Brush, Rgb, MAX_MOVE_TRAIL};
+
 const SQUARE: usize = 90;
-const COLOR_WIDTH: usize = 90 * 2 / 3;
 
+/// Supported square sizes in pixels, in ascending order. The largest is
+/// the native size of the sprite sheet.
+const SIZES: [usize; 3] = [40, 60, SQUARE];
+
+/// Most colors appended by `Theme::palette()`: two each for highlight and
+/// premove squares, piece shadows and every faded move of the trail, the
+/// brushes, and a background color.
+const MAX_EXTRA_COLORS: usize = 2 * 3 + 2 * (MAX_MOVE_TRAIL as usize - 1) + Brush::ALL.len() + 1;
+
+/// Most colors of a sprite sheet, so that indices stay below 128 even
+/// with all colors appended by `Theme::palette()`. Frames with higher
+/// indices would need a minimum code size the GIF encoder gets wrong.
+const MAX_SPRITE_COLORS: usize = 128 - MAX_EXTRA_COLORS;
+
+/// Rows of the sprite sheet: empty squares, one row per role, the king in
+/// check, and the checkmated king, which is derived when loading.
+const ROWS: usize = 9;
+
+/// Every combination has a cell in the sprite sheet. In particular, the
+/// rows of the king in check have highlighted columns as well, so that
+/// check and highlight compose on the same square. Check and mate only
+/// apply to kings.
+#[derive(Clone, Copy)]
 pub struct SpriteKey {
     pub piece: Option<Piece>,
     pub dark_square: bool,
     pub highlight: bool,
     pub check: bool,
+    pub mate: bool,
 }
 
 impl SpriteKey {
     fn x(&self) -> usize {
-        (if self.piece.map_or(false, |p| p.color.is_white()) {
+        (if self.piece.is_some_and(|p| p.color.is_white()) {
             4
         } else {
             0
@@ -24,7 +56,13 @@ impl SpriteKey {
 
     fn y(&self) -> usize {
         match self.piece {
-            Some(piece) if self.check && piece.role == Role::King => 7,
+            Some(piece) if self.check && piece.role == Role::King => {
+                if self.mate {
+                    8
+                } else {
+                    7
+                }
+            }
             Some(piece) => piece.role as usize,
             None => 0,
         }
@@ -34,29 +72,438 @@ impl SpriteKey {
 pub struct Theme {
     color_table_config: ColorTableConfig,
     global_color_table: GlobalColorTable,
+    /// Number of colors used by the sprite sheet. The color table may be
+    /// longer, padded to a power of two.
+    sprite_colors: usize,
+    /// Whether the sprite sheet came with a local color table, which
+    /// rendered images should then use as well.
+    local_color_table: bool,
+    square: usize,
     sprite: Array2<u8>,
+    /// Contiguous copies of the cells of the sprite sheet, row by row, so
+    /// that squares can be copied without slicing on every frame.
+    cells: Vec<Array2<u8>>,
+    /// Factor by which pieces were resized within their squares by
+    /// [`Theme::with_piece_scale()`].
+    piece_scale: f32,
+    font: Font<'static>,
 }
 
 impl Theme {
+    /// Loads the bundled sprite sheet, which is known to be valid.
     pub fn new() -> Theme {
-        let sprite_data = include_bytes!("../theme/sprite.gif") as &[u8];
+        Theme::try_new(include_bytes!("../theme/sprite.gif")).expect("bundled sprite sheet")
+    }
+
+    /// Loads a GIF sprite sheet with the layout of `theme/sprite.gif`:
+    /// 8 by 8 squares of `SQUARE` pixels, all in the first image.
+    pub fn try_new(sprite_data: &[u8]) -> Result<Theme, ThemeError> {
         let mut decoder = gift::Decoder::new(std::io::Cursor::new(sprite_data)).into_frames();
-        let preamble = decoder
-            .preamble()
-            .expect("decode preamble")
-            .expect("preamble");
-        let frame = decoder.next().expect("frame").expect("decode frame");
+        let preamble = decoder.preamble()?.ok_or(ThemeError::MissingImage)?;
+        let frame = decoder.next().ok_or(ThemeError::MissingImage)??;
+        let got = (
+            usize::from(frame.image_desc.width()),
+            usize::from(frame.image_desc.height()),
+        );
+        if got != (SQUARE * 8, SQUARE * 8) {
+            return Err(ThemeError::BadSpriteDimensions {
+                expected: (SQUARE * 8, SQUARE * 8),
+                got,
+            });
+        }
+        let (color_table_config, global_color_table, local_color_table) =
+            match frame.local_color_table {
+                Some(ref table) => (
+                    frame.image_desc.color_table_config(),
+                    GlobalColorTable::with_colors(table.colors()),
+                    true,
+                ),
+                None => (
+                    preamble.logical_screen_desc.color_table_config(),
+                    preamble
+                        .global_color_table
+                        .ok_or(ThemeError::MissingColorTable)?,
+                    false,
+                ),
+            };
         let sprite =
             Array2::from_shape_vec((SQUARE * 8, SQUARE * 8), frame.image_data.data().to_owned())
-                .expect("from shape");
+                .map_err(|_| ThemeError::IncompleteImage)?;
+        let sprite = with_mate_row(sprite, global_color_table.colors());
+
+        Ok(Theme {
+            color_table_config,
+            sprite_colors: global_color_table.colors().len() / 3,
+            global_color_table,
+            local_color_table,
+            square: SQUARE,
+            cells: cells(&sprite, SQUARE),
+            sprite,
+            piece_scale: 1.0,
+            font: noto_sans(),
+        })
+    }
+
+    /// Builds a theme without decoding the sprite sheet, with every sprite
+    /// filled with the same `color` index of a grayscale palette. Only the
+    /// swatches for bars and text get colors of their own. Meant for
+    /// benchmarks that measure encoding separately from blitting sprites.
+    ///
+    /// Panics if `color` is not below 64.
+    #[cfg(feature = "bench")]
+    pub fn solid(color: u8) -> Theme {
+        const COLORS: u8 = 64;
+        assert!(color < COLORS, "solid color out of range");
+        let colors: Vec<u8> = (0..COLORS).flat_map(|c| [c * 4; 3]).collect();
+        let mut sprite = Array2::from_elem((SQUARE * ROWS, SQUARE * 8), color);
+        let color_width = SQUARE * 2 / 3;
+        for i in 0..5 {
+            let left = SQUARE * 4 + color_width * i;
+            sprite
+                .slice_mut(s!(..SQUARE, left..(left + color_width)))
+                .fill((color + 1 + i as u8) % COLORS);
+        }
 
         Theme {
-            color_table_config: preamble.logical_screen_desc.color_table_config(),
-            global_color_table: preamble.global_color_table.expect("color table present"),
+            color_table_config: ColorTableConfig::new(
+                ColorTableExistence::Present,
+                ColorTableOrdering::NotSorted,
+                u16::from(COLORS),
+            ),
+            global_color_table: GlobalColorTable::with_colors(&colors),
+            sprite_colors: usize::from(COLORS),
+            local_color_table: false,
+            square: SQUARE,
+            cells: cells(&sprite, SQUARE),
             sprite,
+            piece_scale: 1.0,
+            font: noto_sans(),
         }
     }
 
+    /// Loads a true color sprite sheet, like a PNG, with the same layout
+    /// as `theme/sprite.gif`. Colors are quantized to a palette that only
+    /// depends on the pixels, so the same sheet always gets the same color
+    /// table.
+    #[cfg(feature = "image")]
+    pub fn from_image(data: &[u8]) -> Result<Theme, ThemeError> {
+        let image = image::load_from_memory(data)?.to_rgb8();
+        let (width, height) = image.dimensions();
+        let got = (width as usize, height as usize);
+        if got != (SQUARE * 8, SQUARE * 8) {
+            return Err(ThemeError::BadSpriteDimensions {
+                expected: (SQUARE * 8, SQUARE * 8),
+                got,
+            });
+        }
+
+        // Colors that are looked up by position keep exact entries, so that
+        // quantization can not merge them with others. Most importantly,
+        // only the transparent swatch may use the transparent color.
+        let color_width = SQUARE * 2 / 3;
+        let swatch = |i: usize| SQUARE * 4 + color_width * i;
+        let key_pixel = |x: usize| image.get_pixel(x as u32, 0).0;
+        let mut palette: Vec<[u8; 3]> = Vec::new();
+        for x in (0..4)
+            .map(|i| SQUARE * i)
+            .chain((0..=5).map(|i| swatch(i) + color_width / 2))
+        {
+            if !palette.contains(&key_pixel(x)) {
+                palette.push(key_pixel(x));
+            }
+        }
+        let transparent = key_pixel(swatch(5) + color_width / 2);
+        let transparent_index = palette.iter().position(|&rgb| rgb == transparent);
+        let others: Vec<[u8; 3]> = image
+            .pixels()
+            .map(|pixel| pixel.0)
+            .filter(|rgb| !palette.contains(rgb))
+            .collect();
+        palette.extend(quantize(&others, MAX_SPRITE_COLORS - palette.len()));
+
+        let mut indices = HashMap::new();
+        let sprite = Array2::from_shape_fn((SQUARE * 8, SQUARE * 8), |(y, x)| {
+            let rgb = image.get_pixel(x as u32, y as u32).0;
+            let in_swatch = y < SQUARE && x >= swatch(5);
+            *indices.entry((rgb, in_swatch)).or_insert_with(|| {
+                let skip = transparent_index.filter(|_| !in_swatch);
+                nearest(&palette, skip, rgb)
+            })
+        });
+
+        let table_len = palette.len().next_power_of_two();
+        let sprite_colors = palette.len();
+        let mut colors: Vec<u8> = palette.into_iter().flatten().collect();
+        colors.resize(table_len * 3, 0);
+        let global_color_table = GlobalColorTable::with_colors(&colors);
+        let sprite = with_mate_row(sprite, global_color_table.colors());
+
+        Ok(Theme {
+            color_table_config: ColorTableConfig::new(
+                ColorTableExistence::Present,
+                ColorTableOrdering::NotSorted,
+                table_len as u16,
+            ),
+            global_color_table,
+            sprite_colors,
+            local_color_table: false,
+            square: SQUARE,
+            cells: cells(&sprite, SQUARE),
+            sprite,
+            piece_scale: 1.0,
+            font: noto_sans(),
+        })
+    }
+
+    /// Resamples the sprite sheet (nearest neighbor) to render squares of
+    /// the given size.
+    pub fn scaled(&self, square: usize) -> Theme {
+        let sprite = Array2::from_shape_fn((square * ROWS, square * 8), |(y, x)| {
+            self.sprite[(
+                y / square * self.square + y % square * self.square / square,
+                x / square * self.square + x % square * self.square / square,
+            )]
+        });
+
+        Theme {
+            color_table_config: self.color_table_config,
+            global_color_table: self.global_color_table.clone(),
+            sprite_colors: self.sprite_colors,
+            local_color_table: self.local_color_table,
+            square,
+            cells: cells(&sprite, square),
+            sprite,
+            piece_scale: self.piece_scale,
+            font: self.font.clone(),
+        }
+    }
+
+    /// Downsamples the sprite sheet like [`Theme::scaled()`], but averages
+    /// all source pixels covered by each target pixel and maps the result
+    /// back to the closest sprite color. The first row with the square
+    /// colors and swatches stays exact, and so does every pixel that would
+    /// blend with the transparent color.
+    pub fn smoothed(&self, square: usize) -> Theme {
+        let scaled = self.scaled(square);
+        let palette: Vec<[u8; 3]> = self
+            .sprite_color_table()
+            .chunks(3)
+            .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+            .collect();
+        let transparent = self.transparent_color();
+        let span = |t: usize| {
+            let (cell, offset) = (t / square * self.square, t % square);
+            let start = cell + offset * self.square / square;
+            start..(cell + (offset + 1) * self.square / square).max(start + 1)
+        };
+
+        let mut indices = HashMap::new();
+        let sprite = Array2::from_shape_fn((square * ROWS, square * 8), |(y, x)| {
+            let block = self.sprite.slice(s![span(y), span(x)]);
+            let first = block[(0, 0)];
+            if y < square || block.iter().any(|&c| c == transparent) {
+                return scaled.sprite[(y, x)];
+            } else if block.iter().all(|&c| c == first) {
+                return first;
+            }
+            let mut sum = [0; 3];
+            for &c in block.iter() {
+                for (s, c) in sum.iter_mut().zip(self.rgb(c)) {
+                    *s += usize::from(c);
+                }
+            }
+            let n = block.len();
+            let rgb = sum.map(|s| ((s + n / 2) / n) as u8);
+            *indices
+                .entry(rgb)
+                .or_insert_with(|| nearest(&palette, Some(usize::from(transparent)), rgb))
+        });
+
+        Theme {
+            cells: cells(&sprite, square),
+            sprite,
+            ..scaled
+        }
+    }
+
+    /// Stretches the luma of all colors away from the midpoint between the
+    /// square colors, so that light squares get lighter and dark squares
+    /// darker. Hue is kept, and so are anti-aliased piece edges, since
+    /// every color of the sprite sheet is mapped the same way.
+    pub fn high_contrast(&self) -> Theme {
+        let luma = |rgb: [u8; 3]| {
+            (299 * i32::from(rgb[0]) + 587 * i32::from(rgb[1]) + 114 * i32::from(rgb[2])) / 1000
+        };
+        let mid = (luma(self.rgb(self.light_square_color()))
+            + luma(self.rgb(self.dark_square_color())))
+            / 2;
+        let colors: Vec<u8> = self
+            .global_color_table
+            .colors()
+            .chunks(3)
+            .flat_map(|rgb| {
+                let rgb = [rgb[0], rgb[1], rgb[2]];
+                let shift = (luma(rgb) - mid) * 3 / 2;
+                rgb.map(|c| (i32::from(c) + shift).clamp(0, 255) as u8)
+            })
+            .collect();
+
+        Theme {
+            color_table_config: self.color_table_config,
+            global_color_table: GlobalColorTable::with_colors(&colors),
+            sprite_colors: self.sprite_colors,
+            local_color_table: self.local_color_table,
+            square: self.square,
+            sprite: self.sprite.clone(),
+            cells: self.cells.clone(),
+            piece_scale: self.piece_scale,
+            font: self.font.clone(),
+        }
+    }
+
+    /// Adds a wood grain to the squares, with slightly darker and lighter
+    /// shades of the flat square colors in wavy bands. The texture is the
+    /// same on every square of a color and also shows around pieces. The
+    /// swatches and the top left pixel of each square, where the square
+    /// colors are sampled, stay flat.
+    pub fn wood(&self) -> Theme {
+        let mut colors = self.sprite_color_table().to_vec();
+        let flat = [self.light_square_color(), self.dark_square_color()];
+        let shades = flat.map(|color| {
+            let rgb = self.rgb(color);
+            [92, 106].map(|percent: u16| {
+                let index = (colors.len() / 3) as u8;
+                colors.extend(rgb.map(|c| (u16::from(c) * percent / 100).min(255) as u8));
+                index
+            })
+        });
+        let sprite_colors = colors.len() / 3;
+        let table_len = sprite_colors.next_power_of_two();
+        colors.resize(table_len * 3, 0);
+
+        let square = self.square;
+        let sprite = Array2::from_shape_fn(self.sprite.dim(), |(y, x)| {
+            let color = self.sprite[(y, x)];
+            let dark_square = match flat.iter().position(|&c| c == color) {
+                Some(dark_square) if y >= square || x < square * 4 => dark_square,
+                _ => return color,
+            };
+            let (u, v) = (
+                (x % square) as f32 / square as f32,
+                (y % square) as f32 / square as f32,
+            );
+            let band =
+                (0.3 + v * 7.0 + (u * 6.3).sin() * 0.35 + (u * 2.1 + v * 3.0).sin() * 0.2).fract();
+            if band < 0.12 {
+                shades[dark_square][0]
+            } else if (0.5..0.58).contains(&band) {
+                shades[dark_square][1]
+            } else {
+                color
+            }
+        });
+
+        Theme {
+            color_table_config: ColorTableConfig::new(
+                ColorTableExistence::Present,
+                ColorTableOrdering::NotSorted,
+                table_len as u16,
+            ),
+            global_color_table: GlobalColorTable::with_colors(&colors),
+            sprite_colors,
+            local_color_table: self.local_color_table,
+            square,
+            cells: cells(&sprite, square),
+            sprite,
+            piece_scale: self.piece_scale,
+            font: self.font.clone(),
+        }
+    }
+
+    /// Enlarges (or with a factor below 1, shrinks) the pieces within their
+    /// squares, for piece sets that are drawn with more padding than the
+    /// bundled one. Pieces are resampled around the center of the square
+    /// (nearest neighbor) and clipped to it. A factor of 1 keeps the sprite
+    /// sheet as it is. Factors of repeated calls multiply.
+    ///
+    /// Panics unless `scale` is positive and finite.
+    pub fn with_piece_scale(&self, scale: f32) -> Theme {
+        assert!(
+            scale > 0.0 && scale.is_finite(),
+            "piece scale must be positive"
+        );
+        let square = self.square;
+        let center = square as f32 / 2.0;
+        let source = |t: usize| {
+            let s = ((t as f32 + 0.5 - center) / scale + center).floor();
+            (0.0..square as f32).contains(&s).then_some(s as usize)
+        };
+        let sprite = Array2::from_shape_fn(self.sprite.dim(), |(y, x)| {
+            let (row, col) = (y / square, x / square);
+            if row == 0 {
+                return self.sprite[(y, x)];
+            }
+            let at = |row: usize, col: usize, y: usize, x: usize| {
+                self.sprite[(row * square + y, col * square + x)]
+            };
+            // Empty squares are in the first columns of the first row. The
+            // king in check has no empty counterpart, so the plain king
+            // tells which pixels belong to the piece.
+            let empty = |y: usize, x: usize| at(0, col % 4, y, x);
+            let piece = |y: usize, x: usize| at(row.min(6), col, y, x) != empty(y, x);
+            let (ty, tx) = (y % square, x % square);
+            match (source(ty), source(tx)) {
+                (Some(sy), Some(sx)) if piece(sy, sx) => at(row, col, sy, sx),
+                _ if !piece(ty, tx) => at(row, col, ty, tx),
+                // Uncovered parts of the king in check take the glow from
+                // closer to the center.
+                (Some(sy), Some(sx)) if row > 6 => at(row, col, sy, sx),
+                _ => empty(ty, tx),
+            }
+        });
+
+        Theme {
+            color_table_config: self.color_table_config,
+            global_color_table: self.global_color_table.clone(),
+            sprite_colors: self.sprite_colors,
+            local_color_table: self.local_color_table,
+            square,
+            cells: cells(&sprite, square),
+            sprite,
+            piece_scale: self.piece_scale * scale,
+            font: self.font.clone(),
+        }
+    }
+
+    /// Replaces the color table, translating the sprite sheet from old to
+    /// new color indices with `map`. The first `sprite_colors` entries of
+    /// the new table are in use.
+    fn remapped(
+        &self,
+        color_table_config: ColorTableConfig,
+        colors: &[u8],
+        sprite_colors: usize,
+        map: &[u8],
+    ) -> Theme {
+        let sprite = self.sprite.mapv(|color| map[usize::from(color)]);
+        Theme {
+            color_table_config,
+            global_color_table: GlobalColorTable::with_colors(colors),
+            sprite_colors,
+            local_color_table: self.local_color_table,
+            square: self.square,
+            cells: cells(&sprite, self.square),
+            sprite,
+            piece_scale: self.piece_scale,
+            font: self.font.clone(),
+        }
+    }
+
+    /// The used part of the color table.
+    fn sprite_color_table(&self) -> &[u8] {
+        &self.global_color_table.colors()[..self.sprite_colors * 3]
+    }
+
     pub fn color_table_config(&self) -> ColorTableConfig {
         self.color_table_config
     }
@@ -65,36 +512,936 @@ impl Theme {
         &self.global_color_table
     }
 
+    /// Builds the color table for a render. A custom highlight color is
+    /// blended onto both square colors, and shadow colors, brush colors
+    /// for annotations and a custom background color are added if
+    /// needed. Extra colors are appended to the sprite colors, replacing
+    /// any padding. `trail` is the number of older moves highlighted with
+    /// fading intensity.
+    pub fn palette(
+        &self,
+        highlight: Option<Rgb>,
+        brushes: bool,
+        background: Option<Rgb>,
+        trail: usize,
+        premove: bool,
+        shadows: bool,
+    ) -> Palette {
+        if highlight.is_none()
+            && !brushes
+            && background.is_none()
+            && trail == 0
+            && !premove
+            && !shadows
+        {
+            return Palette {
+                color_table_config: self.color_table_config,
+                global_color_table: self.global_color_table.clone(),
+                local: self.local_color_table,
+                highlight: None,
+                muted: Vec::new(),
+                premove: None,
+                shadows: None,
+                brushes: None,
+                background: self.bar_color(),
+                text: self.text_color(),
+            };
+        }
+
+        let mut colors = self.sprite_color_table().to_vec();
+
+        let square_rgb = |dark_square: bool| {
+            self.rgb(if dark_square {
+                self.dark_square_color()
+            } else {
+                self.light_square_color()
+            })
+        };
+        let highlight_rgb = [false, true].map(|dark_square| match highlight {
+            Some(highlight) => blend(square_rgb(dark_square), highlight.0),
+            None => self.rgb(self.highlight_color(dark_square)),
+        });
+
+        let highlight = highlight.map(|_| {
+            [false, true].map(|dark_square| {
+                let index = (colors.len() / 3) as u8;
+                colors.extend(highlight_rgb[usize::from(dark_square)]);
+                (self.highlight_color(dark_square), index)
+            })
+        });
+
+        // The previous move alone is blended halfway onto the square.
+        let muted = (0..trail)
+            .map(|age| {
+                [false, true].map(|dark_square| {
+                    let index = (colors.len() / 3) as u8;
+                    let (square, highlight) = (
+                        square_rgb(dark_square),
+                        highlight_rgb[usize::from(dark_square)],
+                    );
+                    colors.extend([0, 1, 2].map(|i| {
+                        ((usize::from(square[i]) * (age + 1)
+                            + usize::from(highlight[i]) * (trail - age))
+                            / (trail + 1)) as u8
+                    }));
+                    (self.highlight_color(dark_square), index)
+                })
+            })
+            .collect();
+
+        let premove = premove.then(|| {
+            [false, true].map(|dark_square| {
+                let index = (colors.len() / 3) as u8;
+                colors.extend(blend(square_rgb(dark_square), PREMOVE_RGB));
+                (self.highlight_color(dark_square), index)
+            })
+        });
+
+        let shadows = shadows.then(|| {
+            let index = (colors.len() / 3) as u8;
+            for dark_square in [false, true] {
+                colors.extend(square_rgb(dark_square).map(|c| (u16::from(c) * 3 / 4) as u8));
+            }
+            index
+        });
+
+        let brushes = brushes.then(|| {
+            let index = (colors.len() / 3) as u8;
+            for brush in Brush::ALL {
+                colors.extend(brush.rgb().0);
+            }
+            index
+        });
+
+        let background = background.map_or(self.bar_color(), |background| {
+            let index = (colors.len() / 3) as u8;
+            colors.extend(background.0);
+            index
+        });
+
+        let table_len = (colors.len() / 3).next_power_of_two();
+        colors.resize(table_len * 3, 0);
+        Palette {
+            color_table_config: ColorTableConfig::new(
+                ColorTableExistence::Present,
+                ColorTableOrdering::NotSorted,
+                table_len as u16,
+            ),
+            global_color_table: GlobalColorTable::with_colors(&colors),
+            local: self.local_color_table,
+            highlight,
+            muted,
+            premove,
+            shadows,
+            brushes,
+            background,
+            text: self.text_color(),
+        }
+    }
+
+    pub fn light_square_color(&self) -> u8 {
+        self.sprite[(0, 0)]
+    }
+
+    pub fn dark_square_color(&self) -> u8 {
+        self.sprite[(0, self.square)]
+    }
+
+    fn rgb(&self, color: u8) -> [u8; 3] {
+        let rgb = &self.global_color_table.colors()[usize::from(color) * 3..][..3];
+        [rgb[0], rgb[1], rgb[2]]
+    }
+
+    fn highlight_color(&self, dark_square: bool) -> u8 {
+        self.sprite[(0, self.square * if dark_square { 3 } else { 2 })]
+    }
+
+    fn color_width(&self) -> usize {
+        self.square * 2 / 3
+    }
+
+    fn swatch(&self, i: usize) -> u8 {
+        // Sample the center, so that swatches survive downsampling.
+        self.sprite[(
+            0,
+            self.square * 4 + self.color_width() * i + self.color_width() / 2,
+        )]
+    }
+
     pub fn bar_color(&self) -> u8 {
-        self.sprite[(0, SQUARE * 4)]
+        self.swatch(0)
+    }
+
+    pub fn text_color(&self) -> u8 {
+        self.swatch(1)
+    }
+
+    pub fn gold_color(&self) -> u8 {
+        self.swatch(2)
+    }
+
+    pub fn bot_color(&self) -> u8 {
+        self.swatch(3)
+    }
+
+    pub fn med_text_color(&self) -> u8 {
+        self.swatch(4)
     }
 
     pub fn transparent_color(&self) -> u8 {
-        self.sprite[(0, SQUARE * 4 + COLOR_WIDTH * 5)]
+        self.swatch(5)
+    }
+
+    pub fn piece_scale(&self) -> f32 {
+        self.piece_scale
     }
 
     pub fn square(&self) -> usize {
-        SQUARE
+        self.square
     }
 
-    pub fn width(&self) -> usize {
-        self.square() * 8
+    pub fn eval_bar_width(&self) -> usize {
+        self.square / 4
+    }
+
+    pub fn move_list_width(&self) -> usize {
+        self.square * 2
     }
 
     pub fn bar_height(&self) -> usize {
-        60
+        self.square * 2 / 3
+    }
+
+    pub fn pocket_height(&self) -> usize {
+        self.square / 2
+    }
+
+    pub fn coordinate_margin(&self) -> usize {
+        self.square / 3
+    }
+
+    pub fn font(&self) -> &Font<'static> {
+        &self.font
+    }
+
+    pub fn sprite(&self, key: SpriteKey) -> ArrayView2<'_, u8> {
+        self.cells[key.y() * 8 + key.x()].view()
+    }
+
+    /// Sprite with the piece turned upside down, on an upright square. The
+    /// king in check is turned as a whole, together with its glow.
+    pub fn rotated_sprite(&self, key: SpriteKey) -> Array2<u8> {
+        let turned = self.sprite(key).slice(s![..;-1, ..;-1]).to_owned();
+        match self.piece_mask(key) {
+            Some(mask) => {
+                let mut cell = self.sprite(SpriteKey { piece: None, ..key }).to_owned();
+                Zip::from(&mut cell)
+                    .and(&turned)
+                    .and(mask.slice(s![..;-1, ..;-1]))
+                    .for_each(|c, &t, &m| {
+                        if m {
+                            *c = t;
+                        }
+                    });
+                cell
+            }
+            None => turned,
+        }
+    }
+
+    /// Pixels of a sprite that belong to the piece, found by comparing
+    /// with the empty square. `None` for empty squares and the king in
+    /// check, which has no empty counterpart.
+    pub fn piece_mask(&self, key: SpriteKey) -> Option<Array2<bool>> {
+        if key.piece.is_none() || key.y() >= 7 {
+            return None;
+        }
+        let empty = self.sprite(SpriteKey { piece: None, ..key });
+        Some(
+            Zip::from(&self.sprite(key))
+                .and(&empty)
+                .map_collect(|piece, empty| piece != empty),
+        )
+    }
+
+    /// Offset of piece shadows to the bottom right, in pixels.
+    pub fn shadow_offset(&self) -> usize {
+        (self.square / 30).max(1)
+    }
+}
+
+/// Reduces the colors of an image with median cut: starting from a single
+/// box of all distinct colors, the box with the widest channel is split at
+/// the median pixel, until there are enough boxes. Each box becomes the
+/// average of its pixels. Colors are visited in sorted order and ties go to
+/// the first box, so the result is deterministic.
+#[cfg(feature = "image")]
+fn quantize(pixels: &[[u8; 3]], max_colors: usize) -> Vec<[u8; 3]> {
+    let mut histogram: HashMap<[u8; 3], u64> = HashMap::new();
+    for &rgb in pixels {
+        *histogram.entry(rgb).or_default() += 1;
+    }
+    let mut colors: Vec<([u8; 3], u64)> = histogram.into_iter().collect();
+    colors.sort_unstable();
+    if colors.len() <= max_colors {
+        return colors.into_iter().map(|(rgb, _)| rgb).collect();
+    }
+
+    let widest = |colors: &[([u8; 3], u64)]| {
+        (0..3)
+            .map(|channel| {
+                let values = colors.iter().map(|(rgb, _)| rgb[channel]);
+                let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+                (range, channel)
+            })
+            .max_by_key(|&(range, channel)| (range, Reverse(channel)))
+            .expect("channels")
+    };
+
+    let mut boxes = vec![colors];
+    while boxes.len() < max_colors {
+        let (i, (range, channel)) = boxes
+            .iter()
+            .map(|colors| widest(colors))
+            .enumerate()
+            .max_by_key(|&(i, range)| (range.0, Reverse(i)))
+            .expect("boxes");
+        if range == 0 {
+            break;
+        }
+        let mut lower = boxes.remove(i);
+        lower.sort_unstable_by_key(|&(rgb, _)| (rgb[channel], rgb));
+        let total: u64 = lower.iter().map(|&(_, count)| count).sum();
+        let mut seen = 0;
+        let median = lower
+            .iter()
+            .position(|&(_, count)| {
+                seen += count;
+                seen * 2 >= total
+            })
+            .unwrap_or(0);
+        let upper = lower.split_off((median + 1).min(lower.len() - 1));
+        boxes.insert(i, upper);
+        boxes.insert(i, lower);
     }
 
-    pub fn height(&self) -> usize {
-        self.width()
+    boxes
+        .iter()
+        .map(|colors| {
+            let total: u64 = colors.iter().map(|&(_, count)| count).sum();
+            [0, 1, 2].map(|channel| {
+                let sum: u64 = colors
+                    .iter()
+                    .map(|&(rgb, count)| u64::from(rgb[channel]) * count)
+                    .sum();
+                ((sum + total / 2) / total) as u8
+            })
+        })
+        .collect()
+}
+
+/// Finds the index of the closest palette color other than `skip`,
+/// preferring lower indices.
+fn nearest(palette: &[[u8; 3]], skip: Option<usize>, rgb: [u8; 3]) -> u8 {
+    palette
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| Some(i) != skip)
+        .min_by_key(|&(i, color)| {
+            let distance: i32 = (0..3)
+                .map(|c| (i32::from(color[c]) - i32::from(rgb[c])).pow(2))
+                .sum();
+            (distance, i)
+        })
+        .map_or(0, |(i, _)| i as u8)
+}
+
+fn noto_sans() -> Font<'static> {
+    Font::try_from_bytes(include_bytes!("../theme/NotoSans-Regular.ttf") as &[u8])
+        .expect("parse font")
+}
+
+fn cells(sprite: &Array2<u8>, square: usize) -> Vec<Array2<u8>> {
+    (0..ROWS)
+        .flat_map(|y| (0..8).map(move |x| (y, x)))
+        .map(|(y, x)| {
+            sprite
+                .slice(s!(
+                    (square * y)..(square + square * y),
+                    (square * x)..(square + square * x)
+                ))
+                .to_owned()
+        })
+        .collect()
+}
+
+/// Appends a row with the checkmated king, derived from the king in check
+/// by doubling the tint of the check gradient. Colors are snapped to the
+/// nearest color of the king rows, so that the palette stays the same.
+fn with_mate_row(sprite: Array2<u8>, colors: &[u8]) -> Array2<u8> {
+    let rgb = |color: u8| {
+        let rgb = &colors[usize::from(color) * 3..][..3];
+        [rgb[0], rgb[1], rgb[2]].map(i32::from)
+    };
+    let king = sprite.slice(s!((SQUARE * 6)..(SQUARE * 7), ..));
+    let check = sprite.slice(s!((SQUARE * 7)..(SQUARE * 8), ..));
+    let mut candidates: Vec<u8> = king.iter().chain(check.iter()).copied().collect();
+    candidates.sort_unstable();
+    candidates.dedup();
+    let nearest = |target: [i32; 3]| {
+        candidates
+            .iter()
+            .copied()
+            .min_by_key(|&color| {
+                let rgb = rgb(color);
+                (0..3).map(|i| (rgb[i] - target[i]).pow(2)).sum::<i32>()
+            })
+            .expect("king colors")
+    };
+    let mate = Array2::from_shape_fn((SQUARE, SQUARE * 8), |(y, x)| {
+        let (plain, tinted) = (king[(y, x)], check[(y, x)]);
+        if plain == tinted {
+            tinted
+        } else {
+            let (plain, tinted) = (rgb(plain), rgb(tinted));
+            nearest([0, 1, 2].map(|i| (2 * tinted[i] - plain[i]).clamp(0, 255)))
+        }
+    });
+    concatenate![Axis(0), sprite, mate]
+}
+
+#[derive(Debug)]
+pub enum ThemeError {
+    Decode(gift::Error),
+    #[cfg(feature = "image")]
+    Image(image::ImageError),
+    MissingImage,
+    MissingColorTable,
+    IncompleteImage,
+    /// Width and height in pixels.
+    BadSpriteDimensions {
+        expected: (usize, usize),
+        got: (usize, usize),
+    },
+}
+
+impl fmt::Display for ThemeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThemeError::Decode(err) => write!(f, "could not decode sprite sheet: {}", err),
+            #[cfg(feature = "image")]
+            ThemeError::Image(err) => write!(f, "could not decode sprite sheet: {}", err),
+            ThemeError::MissingImage => f.write_str("sprite sheet has no image"),
+            ThemeError::MissingColorTable => f.write_str("sprite sheet has no color table"),
+            ThemeError::IncompleteImage => f.write_str("sprite sheet image data is incomplete"),
+            ThemeError::BadSpriteDimensions { expected, got } => write!(
+                f,
+                "sprite sheet is {}x{} pixels, expected {}x{}",
+                got.0, got.1, expected.0, expected.1
+            ),
+        }
+    }
+}
+
+impl error::Error for ThemeError {}
+
+impl From<gift::Error> for ThemeError {
+    fn from(err: gift::Error) -> ThemeError {
+        ThemeError::Decode(err)
+    }
+}
+
+#[cfg(feature = "image")]
+impl From<image::ImageError> for ThemeError {
+    fn from(err: image::ImageError) -> ThemeError {
+        ThemeError::Image(err)
+    }
+}
+
+#[derive(Clone)]
+pub struct Palette {
+    color_table_config: ColorTableConfig,
+    global_color_table: GlobalColorTable,
+    local: bool,
+    highlight: Option<[(u8, u8); 2]>,
+    /// Recolors for the moves before the last one, newest first.
+    muted: Vec<[(u8, u8); 2]>,
+    premove: Option<[(u8, u8); 2]>,
+    shadows: Option<u8>,
+    brushes: Option<u8>,
+    background: u8,
+    text: u8,
+}
+
+impl Palette {
+    /// Replaces all colors with their luma, if `grayscale`. Color indices
+    /// stay the same.
+    pub fn grayscale(self, grayscale: bool) -> Palette {
+        if !grayscale {
+            return self;
+        }
+        let colors: Vec<u8> = self
+            .global_color_table
+            .colors()
+            .chunks(3)
+            .flat_map(|rgb| {
+                let luma =
+                    (299 * u32::from(rgb[0]) + 587 * u32::from(rgb[1]) + 114 * u32::from(rgb[2]))
+                        / 1000;
+                [luma as u8; 3]
+            })
+            .collect();
+        Palette {
+            global_color_table: GlobalColorTable::with_colors(&colors),
+            ..self
+        }
+    }
+
+    /// Swaps the colors of the player bars for light bars with dark text.
+    /// A custom background is kept, but still gets dark text.
+    pub fn with_bar_style(self, theme: &Theme, bar_style: BarStyle) -> Palette {
+        match bar_style {
+            BarStyle::Dark => self,
+            BarStyle::Light => Palette {
+                background: if self.background == theme.bar_color() {
+                    theme.text_color()
+                } else {
+                    self.background
+                },
+                text: theme.bar_color(),
+                ..self
+            },
+        }
+    }
+
+    /// Replaces the color index used to fill areas around the board.
+    pub fn with_background(self, background: u8) -> Palette {
+        Palette { background, ..self }
+    }
+
+    pub fn color_table_config(&self) -> ColorTableConfig {
+        self.color_table_config
+    }
+
+    pub fn global_color_table(&self) -> &GlobalColorTable {
+        &self.global_color_table
+    }
+
+    /// Gets the same colors as a local color table, if the theme wants
+    /// them repeated for every image instead of a single global table.
+    pub fn local_color_table(&self) -> Option<LocalColorTable> {
+        self.local
+            .then(|| LocalColorTable::with_colors(self.global_color_table.colors()))
+    }
+
+    /// Gets the highlight color of the sprite sheet and the custom color
+    /// it should be replaced with, if any.
+    pub fn recolor_highlight(&self, dark_square: bool) -> Option<(u8, u8)> {
+        self.highlight
+            .map(|recolor| recolor[usize::from(dark_square)])
+    }
+
+    /// Like `recolor_highlight()`, but for the fainter highlights of older
+    /// moves, if enabled. `age` is 0 for the move before the last one.
+    pub fn recolor_muted(&self, dark_square: bool, age: usize) -> Option<(u8, u8)> {
+        self.muted
+            .get(age)
+            .map(|recolor| recolor[usize::from(dark_square)])
+    }
+
+    /// Like `recolor_highlight()`, but for the bluish highlight of a
+    /// queued premove, if enabled.
+    pub fn recolor_premove(&self, dark_square: bool) -> Option<(u8, u8)> {
+        self.premove
+            .map(|recolor| recolor[usize::from(dark_square)])
+    }
+
+    /// Gets the color of piece shadows on a light or dark square, if
+    /// enabled.
+    pub fn shadow_color(&self, dark_square: bool) -> Option<u8> {
+        self.shadows.map(|index| index + u8::from(dark_square))
+    }
+
+    /// Gets the color index of a brush. Panics if the palette was built
+    /// without brushes.
+    pub fn brush_color(&self, brush: Brush) -> u8 {
+        self.brushes.expect("palette with brushes") + brush as u8
+    }
+
+    /// Gets the color index used to fill the player bars and other areas
+    /// around the board.
+    pub fn background(&self) -> u8 {
+        self.background
+    }
+
+    /// Gets the color index of names, active clocks and other prominent
+    /// text on the background.
+    pub fn text(&self) -> u8 {
+        self.text
+    }
+}
+
+/// Tint of premove squares, half blended onto the square color like the
+/// premove highlight of lichess.
+const PREMOVE_RGB: [u8; 3] = [20, 30, 85];
+
+fn blend(a: [u8; 3], b: [u8; 3]) -> [u8; 3] {
+    [0, 1, 2].map(|i| ((u16::from(a[i]) + u16::from(b[i])) / 2) as u8)
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme::new()
+    }
+}
+
+pub struct ByBoardTheme<T> {
+    pub brown: T,
+    pub high_contrast: T,
+    pub wood: T,
+}
+
+impl<T> ByBoardTheme<T> {
+    pub fn get(&self, board_theme: BoardTheme) -> &T {
+        match board_theme {
+            BoardTheme::Brown => &self.brown,
+            BoardTheme::HighContrast => &self.high_contrast,
+            BoardTheme::Wood => &self.wood,
+        }
+    }
+}
+
+pub struct Themes {
+    themes: ByBoardTheme<Vec<Theme>>,
+    smooth: bool,
+    sized: Mutex<HashMap<(BoardTheme, usize), &'static Theme>>,
+    dense: Mutex<HashMap<(BoardTheme, usize, u8), &'static Theme>>,
+}
+
+impl Themes {
+    pub fn new() -> Themes {
+        Themes::with_smoothing(false)
     }
 
-    pub fn sprite(&self, key: SpriteKey) -> ArrayView2<u8> {
-        let y = key.y();
-        let x = key.x();
-        self.sprite.slice(s!(
-            (SQUARE * y)..(SQUARE + SQUARE * y),
-            (SQUARE * x)..(SQUARE + SQUARE * x)
-        ))
+    /// Like [`Themes::new()`], but with smaller square sizes downsampled
+    /// by [`Theme::smoothed()`] rather than nearest neighbor.
+    pub fn with_smoothing(smooth: bool) -> Themes {
+        Themes::with_options(smooth, 1.0)
+    }
+
+    /// Like [`Themes::with_smoothing()`], but also with the pieces of every
+    /// board theme resized by [`Theme::with_piece_scale()`], before any
+    /// other square sizes are derived.
+    pub fn with_options(smooth: bool, piece_scale: f32) -> Themes {
+        let native = Theme::new();
+        let native = if piece_scale == 1.0 {
+            native
+        } else {
+            native.with_piece_scale(piece_scale)
+        };
+        let high_contrast = native.high_contrast();
+        let wood = native.wood();
+        let [brown, high_contrast, wood] = Themes::shared_palette([native, high_contrast, wood]);
+        Themes {
+            themes: ByBoardTheme {
+                brown: Themes::sizes(brown, smooth),
+                high_contrast: Themes::sizes(high_contrast, smooth),
+                wood: Themes::sizes(wood, smooth),
+            },
+            smooth,
+            sized: Mutex::new(HashMap::new()),
+            dense: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Merges the color tables of all board themes into one, so that every
+    /// theme renders with the same palette. Colors of the first theme keep
+    /// their indices. Colors of other themes reuse an equal entry if there
+    /// is one, or are appended otherwise. The transparent color is never
+    /// shared with an opaque one.
+    fn shared_palette<const N: usize>(themes: [Theme; N]) -> [Theme; N] {
+        let (first, rest) = themes.split_first().expect("at least one theme");
+        let mut colors: Vec<&[u8]> = first.sprite_color_table().chunks(3).collect();
+        let shared_transparent = usize::from(first.transparent_color());
+        let maps: Vec<Vec<u8>> = iter::once((0..colors.len()).map(|i| i as u8).collect())
+            .chain(rest.iter().map(|theme| {
+                let transparent = usize::from(theme.transparent_color());
+                theme
+                    .sprite_color_table()
+                    .chunks(3)
+                    .enumerate()
+                    .map(|(i, rgb)| {
+                        let existing = if i == transparent {
+                            Some(shared_transparent).filter(|&t| colors[t] == rgb)
+                        } else if colors.get(i) == Some(&rgb) && i != shared_transparent {
+                            Some(i)
+                        } else {
+                            (0..colors.len()).find(|&j| colors[j] == rgb && j != shared_transparent)
+                        };
+                        existing.unwrap_or_else(|| {
+                            colors.push(rgb);
+                            colors.len() - 1
+                        }) as u8
+                    })
+                    .collect()
+            }))
+            .collect();
+        assert!(
+            colors.len() <= MAX_SPRITE_COLORS,
+            "too many colors for a shared palette"
+        );
+
+        let sprite_colors = colors.len();
+        let table_len = sprite_colors.next_power_of_two();
+        let mut colors: Vec<u8> = colors.concat();
+        colors.resize(table_len * 3, 0);
+        let color_table_config = ColorTableConfig::new(
+            ColorTableExistence::Present,
+            ColorTableOrdering::NotSorted,
+            table_len as u16,
+        );
+        let mut maps = maps.into_iter();
+        themes.map(|theme| {
+            theme.remapped(
+                color_table_config,
+                &colors,
+                sprite_colors,
+                &maps.next().expect("map per theme"),
+            )
+        })
+    }
+
+    fn sizes(native: Theme, smooth: bool) -> Vec<Theme> {
+        let mut themes: Vec<Theme> = SIZES
+            .iter()
+            .filter(|&&size| size != native.square())
+            .map(|&size| {
+                if smooth {
+                    native.smoothed(size)
+                } else {
+                    native.scaled(size)
+                }
+            })
+            .collect();
+        themes.push(native);
+        themes
+    }
+
+    /// Gets the theme with the largest supported square size not exceeding
+    /// `size`, or the native size if none is requested.
+    pub fn get(&self, size: Option<u16>, board_theme: BoardTheme) -> &Theme {
+        let size = size.map_or(SQUARE, usize::from);
+        let themes = self.themes.get(board_theme);
+        themes
+            .iter()
+            .rev()
+            .find(|theme| theme.square() <= size)
+            .unwrap_or(&themes[0])
+    }
+
+    /// Gets the theme with exactly `square` pixels per square, clamped
+    /// between the smallest supported size and the native size. Sizes
+    /// other than the supported ones are scaled from the native theme on
+    /// first use and kept for the lifetime of the process.
+    pub fn get_sized(&self, board_theme: BoardTheme, square: usize) -> &Theme {
+        let square = square.clamp(SIZES[0], SQUARE);
+        let themes = self.themes.get(board_theme);
+        if let Some(theme) = themes.iter().find(|theme| theme.square() == square) {
+            return theme;
+        }
+        let mut sized = self.sized.lock().expect("sized themes");
+        sized.entry((board_theme, square)).or_insert_with(|| {
+            let native = themes.last().expect("native theme");
+            Box::leak(Box::new(if self.smooth {
+                native.smoothed(square)
+            } else {
+                native.scaled(square)
+            }))
+        })
+    }
+
+    /// Gets the theme like [`Themes::get()`], but with every pixel repeated
+    /// `density` times in each direction, for high-DPI displays. Integer
+    /// scaling keeps the sprites sharp. Scaled themes are kept for the
+    /// lifetime of the process.
+    pub fn get_dense(&self, size: Option<u16>, board_theme: BoardTheme, density: u8) -> &Theme {
+        let theme = self.get(size, board_theme);
+        if density <= 1 {
+            return theme;
+        }
+        let mut dense = self.dense.lock().expect("dense themes");
+        dense
+            .entry((board_theme, theme.square(), density))
+            .or_insert_with(|| {
+                Box::leak(Box::new(
+                    theme.scaled(theme.square() * usize::from(density)),
+                ))
+            })
+    }
+}
+
+impl Default for Themes {
+    fn default() -> Themes {
+        Themes::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use super::*;
+
+    fn themes() -> &'static Themes {
+        static THEMES: OnceLock<Themes> = OnceLock::new();
+        THEMES.get_or_init(Themes::new)
+    }
+
+    fn assert_square(theme: &Theme, square: usize) {
+        assert_eq!(theme.square(), square);
+        let key = SpriteKey {
+            piece: Some(Piece::from_char('N').unwrap()),
+            dark_square: true,
+            highlight: false,
+            check: false,
+            mate: false,
+        };
+        assert_eq!(theme.sprite(key).dim(), (square, square));
+    }
+
+    #[test]
+    fn get_sized() {
+        for square in [50, 75] {
+            let theme = themes().get_sized(BoardTheme::Brown, square);
+            assert_square(theme, square);
+            // Scaled once, then cached.
+            assert!(std::ptr::eq(
+                theme,
+                themes().get_sized(BoardTheme::Brown, square)
+            ));
+            assert!(!std::ptr::eq(
+                theme,
+                themes().get_sized(BoardTheme::Wood, square)
+            ));
+        }
+
+        // Supported sizes are not scaled again.
+        assert!(std::ptr::eq(
+            themes().get_sized(BoardTheme::Brown, 60),
+            themes().get(Some(60), BoardTheme::Brown)
+        ));
+
+        // Sizes out of range are clamped.
+        assert_square(themes().get_sized(BoardTheme::Brown, 1), SIZES[0]);
+        assert_square(themes().get_sized(BoardTheme::Brown, 1000), SQUARE);
+    }
+
+    fn sprite_keys() -> impl Iterator<Item = SpriteKey> {
+        let pieces = iter::once(None).chain(
+            [shakmaty::Color::White, shakmaty::Color::Black]
+                .into_iter()
+                .flat_map(|color| Role::ALL.into_iter().map(move |role| Some(role.of(color)))),
+        );
+        pieces.flat_map(|piece| {
+            (0..16).map(move |bits| SpriteKey {
+                piece,
+                dark_square: bits & 1 != 0,
+                highlight: bits & 2 != 0,
+                check: bits & 4 != 0,
+                mate: bits & 8 != 0,
+            })
+        })
+    }
+
+    #[test]
+    fn every_sprite_key_has_a_cell() {
+        assert_eq!(sprite_keys().count(), 13 * 16);
+        let sized = themes().get_sized(BoardTheme::Wood, 50);
+        for theme in SIZES
+            .iter()
+            .map(|&size| themes().get(Some(size as u16), BoardTheme::Brown))
+            .chain([sized])
+        {
+            let square = theme.square();
+            for key in sprite_keys() {
+                let (x, y) = (key.x(), key.y());
+                assert!(x < 8 && y < ROWS);
+                let king_in_check = key.check && key.piece.is_some_and(|p| p.role == Role::King);
+                assert_eq!(y >= 7, king_in_check);
+                let cell = theme.sprite(key);
+                assert_eq!(cell.dim(), (square, square));
+                assert_eq!(
+                    cell,
+                    theme.sprite.slice(s![
+                        (y * square)..((y + 1) * square),
+                        (x * square)..((x + 1) * square)
+                    ])
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn piece_scale() {
+        let native = Theme::new();
+        assert_eq!(native.piece_scale(), 1.0);
+        let key = |piece| SpriteKey {
+            piece,
+            dark_square: false,
+            highlight: false,
+            check: false,
+            mate: false,
+        };
+        let rook = Some(Piece::from_char('R').unwrap());
+        let pixels = |theme: &Theme| {
+            theme
+                .piece_mask(key(rook))
+                .unwrap()
+                .iter()
+                .filter(|&&m| m)
+                .count()
+        };
+
+        let large = native.with_piece_scale(1.25);
+        assert_eq!(large.piece_scale(), 1.25);
+        assert!(pixels(&large) > pixels(&native));
+        assert_eq!(large.sprite(key(None)), native.sprite(key(None)));
+        assert_eq!(large.with_piece_scale(0.8).piece_scale(), 1.0);
+
+        let themes = Themes::with_options(false, 1.25);
+        for size in SIZES {
+            assert_eq!(
+                themes
+                    .get(Some(size as u16), BoardTheme::Brown)
+                    .piece_scale(),
+                1.25
+            );
+        }
+        assert_eq!(
+            themes.get(None, BoardTheme::Brown).sprite(key(rook)),
+            large.sprite(key(rook))
+        );
+    }
+
+    #[test]
+    fn every_board_theme_loads() {
+        let mut sprites = Vec::new();
+        for board_theme in BoardTheme::ALL {
+            let name = format!("\"{}\"", board_theme.name());
+            assert!(serde_json::from_str::<BoardTheme>(&name).unwrap() == board_theme);
+            for size in SIZES {
+                let theme = themes().get(Some(size as u16), board_theme);
+                assert_eq!(theme.square(), size);
+            }
+            sprites.push(&themes().get(None, board_theme).sprite);
+        }
+        // Themes differ in their square colors or in their sprites.
+        let brown = themes().get(None, BoardTheme::Brown);
+        let high_contrast = themes().get(None, BoardTheme::HighContrast);
+        assert_ne!(
+            brown.rgb(brown.light_square_color()),
+            high_contrast.rgb(high_contrast.light_square_color())
+        );
+        assert_ne!(sprites[0], sprites[2]);
     }
 }
@@ -0,0 +1,111 @@
+use bytes::{BufMut, BytesMut};
+use image_webp::{ColorType, EncodingError, WebPEncoder};
+
+const FLAG_ANIMATION: u8 = 1 << 1;
+const FLAG_ALPHA: u8 = 1 << 4;
+
+const ANMF_NO_BLEND: u8 = 1 << 1;
+
+/// Collects animation frames. The RIFF header has to announce the total
+/// file size, so nothing can be emitted before the last frame is known.
+pub struct Animation {
+    width: usize,
+    height: usize,
+    loop_count: u16,
+    frames: BytesMut,
+}
+
+fn put_u24(output: &mut BytesMut, value: usize) {
+    output.put_uint_le(value as u64, 3);
+}
+
+fn encode_chunk(output: &mut BytesMut, kind: &[u8; 4], data: &[u8]) {
+    output.put_slice(kind);
+    output.put_u32_le(data.len() as u32);
+    output.put_slice(data);
+    if data.len() % 2 == 1 {
+        output.put_u8(0);
+    }
+}
+
+impl Animation {
+    /// Creates an animation that is played `loop_count` times, or forever
+    /// if `0`.
+    pub fn new((width, height): (usize, usize), loop_count: u16) -> Animation {
+        Animation {
+            width,
+            height,
+            loop_count,
+            frames: BytesMut::new(),
+        }
+    }
+
+    /// Adds a frame of palette indices. Frames are offset on even
+    /// coordinates only, so the image is padded with transparent pixels
+    /// where necessary. Unless `blend`, the frame replaces the canvas
+    /// region instead of being drawn on top of it.
+    pub fn add_frame(
+        &mut self,
+        ((left, top), (w, h)): ((usize, usize), (usize, usize)),
+        pixels: &[u8],
+        palette: &[u8],
+        transparent: u8,
+        delay_ms: u32,
+        blend: bool,
+    ) -> Result<(), EncodingError> {
+        let (pad_left, pad_top) = (left % 2, top % 2);
+        let (padded_w, padded_h) = (w + pad_left, h + pad_top);
+
+        let mut rgba = vec![0; padded_w * padded_h * 4];
+        for (y, row) in pixels.chunks(w).take(h).enumerate() {
+            for (x, &idx) in row.iter().enumerate() {
+                if idx == transparent {
+                    continue;
+                }
+                let offset = ((y + pad_top) * padded_w + x + pad_left) * 4;
+                let color = &palette[usize::from(idx) * 3..][..3];
+                rgba[offset..offset + 3].copy_from_slice(color);
+                rgba[offset + 3] = 0xff;
+            }
+        }
+
+        let mut still = Vec::new();
+        WebPEncoder::new(&mut still).encode(
+            &rgba,
+            padded_w as u32,
+            padded_h as u32,
+            ColorType::Rgba8,
+        )?;
+        let vp8l = &still[12..]; // skip RIFF header
+
+        let mut anmf = BytesMut::with_capacity(16 + vp8l.len());
+        put_u24(&mut anmf, (left - pad_left) / 2);
+        put_u24(&mut anmf, (top - pad_top) / 2);
+        put_u24(&mut anmf, padded_w - 1);
+        put_u24(&mut anmf, padded_h - 1);
+        put_u24(&mut anmf, delay_ms.min(0xff_ffff) as usize);
+        anmf.put_u8(if blend { 0 } else { ANMF_NO_BLEND });
+        anmf.put_slice(vp8l);
+        encode_chunk(&mut self.frames, b"ANMF", &anmf);
+        Ok(())
+    }
+
+    pub fn encode(self, output: &mut BytesMut) {
+        let mut vp8x = BytesMut::with_capacity(10);
+        vp8x.put_u8(FLAG_ANIMATION | FLAG_ALPHA);
+        put_u24(&mut vp8x, 0); // reserved
+        put_u24(&mut vp8x, self.width - 1);
+        put_u24(&mut vp8x, self.height - 1);
+
+        let mut anim = BytesMut::with_capacity(6);
+        anim.put_u32_le(0); // transparent background
+        anim.put_u16_le(self.loop_count);
+
+        output.put_slice(b"RIFF");
+        output.put_u32_le((4 + (8 + 10) + (8 + 6) + self.frames.len()) as u32);
+        output.put_slice(b"WEBP");
+        encode_chunk(output, b"VP8X", &vp8x);
+        encode_chunk(output, b"ANIM", &anim);
+        output.put_slice(&self.frames);
+    }
+}